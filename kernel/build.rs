@@ -4,13 +4,25 @@ fn main() {
     //with the println! macro, cargo can be given specific instructions for different aspects of the build
     //More on that here:
     //https://doc.rust-lang.org/cargo/reference/build-scripts.html
-    
+
     //rustc-link-arg tells cargo to pass custom flags to compiler
     //https://doc.rust-lang.org/cargo/reference/build-scripts.html#rustc-link-arg
     //more specific: rustc-link-arg will pass the -C link-arg=FLAG flag to the compile
     //-C is the codegen flag, which can modify the way the code is generated
     //link-arg will append a given flag to the linker invocation
-    println!("cargo:rustc-link-arg=-Tkernel/src/lds/kernel.lds");
+    //The sbi feature links the kernel behind the firmware, both scripts include sections.lds
+    //With builtin-sbi the kernel brings its own firmware and keeps the layout without one
+    //The unit tests are built for the host, they are linked like any other test program
+    if std::env::var("CARGO_CFG_TARGET_ARCH").is_ok_and(|arch| arch != "riscv64") {
+        return;
+    }
+    if std::env::var_os("CARGO_FEATURE_SBI").is_some()
+        && std::env::var_os("CARGO_FEATURE_BUILTIN_SBI").is_none()
+    {
+        println!("cargo:rustc-link-arg=-Tkernel/src/lds/kernel_sbi.lds");
+    } else {
+        println!("cargo:rustc-link-arg=-Tkernel/src/lds/kernel.lds");
+    }
 
     //In this case  "-Tkernel/src/lds/kernel.lds" is appended to the linker invocation
     //The default rust linker is the c linker cc
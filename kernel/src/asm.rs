@@ -1,8 +1,35 @@
 use core::arch::global_asm;
 
-global_asm!(include_str!("asm/boot.S"));
-global_asm!(include_str!("asm/exception.S"));
+/// 1 if the kernel runs in S-mode under an SBI firmware, selects the `.if SBI` blocks of the assembly.
+const SBI: usize = cfg!(feature = "sbi") as usize;
+
+/// 1 if the kernel brings its own SBI layer, selects the `.if BUILTIN_SBI` blocks of the assembly.
+const BUILTIN_SBI: usize = cfg!(feature = "builtin-sbi") as usize;
+
+// The host builds the unit tests, it has nothing to assemble.
+#[cfg(not(test))]
+global_asm!(
+    ".set SBI, {}",
+    ".set BUILTIN_SBI, {}",
+    ".set MAX_HARTS, {}",
+    include_str!("asm/boot.S"),
+    const SBI,
+    const BUILTIN_SBI,
+    const crate::cpu::MAX_HARTS
+);
+#[cfg(not(test))]
+global_asm!(".set SBI, {}", include_str!("asm/exception.S"), const SBI);
+#[cfg(not(test))]
 global_asm!(include_str!("asm/mem.S"));
+#[cfg(all(feature = "builtin-sbi", not(test)))]
+global_asm!(include_str!("asm/sbi.S"));
 extern "C" {
+    /// The entry of every hart, see `boot.S`.
+    pub fn _start();
     pub fn exception();
+    /// Restores the registers from the trap frame at `frame` and returns to the user prog.
+    pub fn exception_return(frame: usize) -> !;
+    /// The M-mode trap handler of the built-in SBI layer.
+    #[cfg(feature = "builtin-sbi")]
+    pub fn sbi_trap();
 }
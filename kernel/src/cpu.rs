@@ -1,3 +1,6 @@
+use crate::hardware::binary_struct::BinaryStruct;
+use riscv_utils::*;
+
 // The frequency of QEMU is 10 MHz
 pub const FREQ: u64 = 10_000_000;
 // Let's do this 250 times per second for switching
@@ -14,8 +17,278 @@ pub const fn build_satp(mode: SatpMode, asid: usize, addr: usize) -> usize {
     (mode as usize) << 60 | (asid & 0xffff) << 44 | (addr >> 12) & 0xff_ffff_ffff
 }
 
-pub fn mscratch_write(val: usize) {
+/// The registers of the trapped program. `exception.S` saves them here and restores them from here.
+/// The layout is fixed, since `exception.S` accesses the fields by offset.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TrapFrame {
+    /// x0 - x31, x0 is never saved.
+    pub regs: [usize; 32], // 0 - 255
+    pub satp: usize, // 256 - 263
+    /// Top of the stack the trap handler runs on.
+    pub trap_stack: usize, // 264 - 271
+    /// The hart the frame belongs to, `exception.S` loads it into tp for [hart_id].
+    pub hart_id: usize, // 272 - 279
+}
+
+impl TrapFrame {
+    pub const fn new() -> Self {
+        TrapFrame {
+            regs: [0; 32],
+            satp: 0,
+            trap_stack: 0,
+            hart_id: 0,
+        }
+    }
+    pub fn a0(&self) -> usize {
+        self.regs[10]
+    }
+    pub fn a1(&self) -> usize {
+        self.regs[11]
+    }
+    pub fn a7(&self) -> usize {
+        self.regs[17]
+    }
+    /// Sets the return value.
+    pub fn set_ret(&mut self, ret: usize) {
+        self.regs[10] = ret;
+    }
+}
+
+/// ABI names of x0 - x31, in the order of [TrapFrame::regs].
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Maximal number of harts, the kernel stack is split between them. Harts with a higher id are parked.
+pub const MAX_HARTS: usize = 8;
+
+/// The trap frames of the harts. The trap scratch register of a hart points to its frame while a user program runs.
+static mut KERNEL_TRAP_FRAMES: [TrapFrame; MAX_HARTS] = [TrapFrame::new(); MAX_HARTS];
+
+/// Returns the trap frame of the current hart.
+pub fn trap_frame() -> &'static mut TrapFrame {
+    unsafe { &mut *core::ptr::addr_of_mut!(KERNEL_TRAP_FRAMES[hart_id()]) }
+}
+
+/// Returns the id of the current hart. The kernel keeps it in tp, `boot.S` and `exception.S` set it.
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) id);
+    }
+    id
+}
+
+/// Returns the frame pointer (s0) of the caller. The kernel is built with frame pointers, see `.cargo/config`.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        core::arch::asm!("mv {}, s0", out(reg) fp);
+    }
+    fp
+}
+
+/// Returns the return address (ra) of the caller. Only valid before the caller calls another function.
+#[inline(always)]
+pub fn return_address() -> usize {
+    let ra: usize;
+    unsafe {
+        core::arch::asm!("mv {}, ra", out(reg) ra);
+    }
+    ra
+}
+
+/// Waits until an interrupt is pending. It wakes up the hart, even if interrupts are turned off.
+pub fn wait_for_interrupt() {
+    unsafe {
+        core::arch::asm!("wfi");
+    }
+}
+
+/// Turns off the interrupts of the mode the kernel runs in (mstatus.MIE or sstatus.SIE).
+/// Returns true if they were on.
+pub fn disable_interrupts() -> bool {
+    let status: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        core::arch::asm!("csrrci {}, mstatus, 1 << 3", out(reg) status);
+        #[cfg(feature = "sbi")]
+        core::arch::asm!("csrrci {}, sstatus, 1 << 1", out(reg) status);
+    }
+    #[cfg(not(feature = "sbi"))]
+    let enabled = BinaryStruct::from(status).is_set(MSTATUS_MIE.0);
+    #[cfg(feature = "sbi")]
+    let enabled = BinaryStruct::from(status).is_set(SSTATUS_SIE.0);
+    enabled
+}
+
+/// Turns the interrupts back on, if they were on before [disable_interrupts].
+pub fn restore_interrupts(enabled: bool) {
+    if !enabled {
+        return;
+    }
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        core::arch::asm!("csrsi mstatus, 1 << 3");
+        #[cfg(feature = "sbi")]
+        core::arch::asm!("csrsi sstatus, 1 << 1");
+    }
+}
+
+/// Returns true if the interrupt with the `code` of mcause or scause is pending for the mode the kernel runs in.
+pub fn interrupt_pending(code: usize) -> bool {
+    let pending: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("mip" => pending);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("sip" => pending);
+    }
+    BinaryStruct::from(pending).is_set(code)
+}
+
+// The kernel handles the traps in M-mode, or in S-mode with the sbi feature.
+// The following functions access the trap registers of the mode the kernel runs in.
+
+/// Interrupt code of the software interrupt in mcause or scause, raised by another hart.
+#[cfg(not(feature = "sbi"))]
+pub const SOFTWARE_INTERRUPT: usize = 3;
+#[cfg(feature = "sbi")]
+pub const SOFTWARE_INTERRUPT: usize = 1;
+/// Interrupt code of the timer interrupt in mcause or scause.
+#[cfg(not(feature = "sbi"))]
+pub const TIMER_INTERRUPT: usize = 7;
+#[cfg(feature = "sbi")]
+pub const TIMER_INTERRUPT: usize = 5;
+/// Interrupt code of the external interrupt in mcause or scause.
+#[cfg(not(feature = "sbi"))]
+pub const EXTERNAL_INTERRUPT: usize = 11;
+#[cfg(feature = "sbi")]
+pub const EXTERNAL_INTERRUPT: usize = 9;
+
+/// Writes mscratch or sscratch.
+pub fn scratch_write(val: usize) {
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        write_machine_reg!(val => "mscratch");
+        #[cfg(feature = "sbi")]
+        write_machine_reg!(val => "sscratch");
+    }
+}
+
+/// Reads mscratch or sscratch.
+pub fn scratch_read() -> usize {
+    let val: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("mscratch" => val);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("sscratch" => val);
+    }
+    val
+}
+
+/// Writes mepc or sepc, the address the trap returns to.
+pub fn epc_write(val: usize) {
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        write_machine_reg!(val => "mepc");
+        #[cfg(feature = "sbi")]
+        write_machine_reg!(val => "sepc");
+    }
+}
+
+/// Reads mepc or sepc, the address the trap came from.
+pub fn epc_read() -> usize {
+    let val: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("mepc" => val);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("sepc" => val);
+    }
+    val
+}
+
+/// Reads mstatus or sstatus.
+pub fn status_read() -> usize {
+    let val: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("mstatus" => val);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("sstatus" => val);
+    }
+    val
+}
+
+/// Reads mcause or scause.
+pub fn cause_read() -> usize {
+    let val: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("mcause" => val);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("scause" => val);
+    }
+    val
+}
+
+/// Reads mtval or stval, the faulting address of a page fault.
+pub fn tval_read() -> usize {
+    let val: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("mtval" => val);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("stval" => val);
+    }
+    val
+}
+
+/// Returns true if the current trap was taken while the kernel itself was running.
+/// The previous privilege mode is only set, if the trap did not come from U-mode.
+pub fn trap_from_kernel() -> bool {
+    let status = BinaryStruct::from(status_read());
+    #[cfg(not(feature = "sbi"))]
+    {
+        let ((mpp_0, _), (mpp_1, _)) = MSTATUS_MPP_U;
+        status.is_set(mpp_0) || status.is_set(mpp_1)
+    }
+    #[cfg(feature = "sbi")]
+    {
+        let (spp, _) = SSTATUS_SPP_U;
+        status.is_set(spp)
+    }
+}
+
+pub fn satp_write(val: usize) {
+    unsafe {
+        write_machine_reg!(val => "satp");
+    }
+}
+
+/// Flushes all cached translations of the address space `asid`.
+pub fn satp_fence_asid(asid: usize) {
+    unsafe {
+        core::arch::asm!("sfence.vma zero, {}", in(reg) asid);
+    }
+}
+
+/// Flushes the cached translation of `vaddr` in the address space `asid`.
+pub fn satp_fence(vaddr: usize, asid: usize) {
+    unsafe {
+        core::arch::asm!("sfence.vma {}, {}", in(reg) vaddr, in(reg) asid);
+    }
+}
+
+/// Makes the hart fetch the instructions written before, e.g. the breakpoints of the gdb stub.
+pub fn instruction_fence() {
     unsafe {
-        llvm_asm!("csrw	mscratch, $0" ::"r"(val));
+        core::arch::asm!("fence.i");
     }
 }
@@ -1,47 +1,62 @@
-use crate::hardware::uart;
-use crate::macros::print;
+use crate::cpu::{self, TrapFrame};
 use crate::{
-    hardware::{binary_struct::BinaryStruct, clint, plic, stack::Stack},
-    scheduler,
+    gdb,
+    hardware::{binary_struct::BinaryStruct, clint, plic},
+    ipi, perf, scheduler, smp, symbols, vma,
 };
 
 use super::system_calls;
-use riscv_utils::*;
 
-#[no_mangle]
-unsafe extern "C" fn exception_handler(mepc: usize, mcause: usize, sp: usize) -> usize {
-    scheduler::save_cur_prog(mepc, sp);
+#[cfg_attr(not(test), no_mangle)]
+unsafe extern "C" fn exception_handler(mepc: usize, mcause: usize, frame: usize) -> usize {
+    let frame = &mut *(frame as *mut TrapFrame);
+    // Before the lock, so the user prog is not charged for waiting on it.
+    let counters = perf::leave_user();
+    // Let the locked pmp entries protect the kernel from itself again.
+    #[cfg(not(feature = "sbi"))]
+    crate::hardware::pmp::unguard_kernel();
+    // Before the lock, a hart that panicked may hold it.
+    ipi::halt_if_requested();
+    // The other harts may be in the kernel
+    smp::lock();
+    scheduler::save_cur_prog(mepc, frame, counters);
     let mut mcause = BinaryStruct::from(mcause);
     let interrupt = mcause.is_set(63);
     if interrupt {
         mcause.at(63, false);
         handle_interrupt(mcause.get());
     } else {
-        handle_exception(mcause.get(), mepc, sp);
+        handle_exception(mcause.get(), mepc);
     }
-    let sp = scheduler::restore_cur_prog();
-    return sp;
+    scheduler::restore_cur_prog(frame);
+    // The trap outlasted a tick of the timer, which interrupts the user prog right after it.
+    if cpu::interrupt_pending(cpu::TIMER_INTERRUPT) {
+        perf::sample_kernel(true);
+    }
+    smp::unlock();
+    return frame as *mut TrapFrame as usize;
 }
 
 unsafe fn handle_interrupt(mcause: usize) {
     match mcause {
-        7 => {
-            // Timer interrupt
-            let next = scheduler::next();
-            scheduler::switch(
-                next.expect("No available next user prog. Idle task not implemented"),
-            );
+        cpu::TIMER_INTERRUPT => {
+            // Timer interrupt. Without another rdy user prog the current one keeps running.
+            // While profiling, the time slice lasts several ticks.
+            if perf::tick(scheduler::cur()) {
+                if let Some(next) = scheduler::next() {
+                    scheduler::switch(next);
+                }
+            }
             clint::set_time_cmp();
         }
-        11 => {
-            // Extern interrupt
-            let irq = plic::read_claim();
-            match irq {
-                plic::IRQ::Uart => {
-                    print!("{}", uart::read_char());
+        cpu::EXTERNAL_INTERRUPT => plic::dispatch(),
+        cpu::SOFTWARE_INTERRUPT => {
+            // Interrupt of another hart
+            if ipi::handle() {
+                if let Some(next) = scheduler::next() {
+                    scheduler::switch(next);
                 }
             }
-            plic::write_complete(irq);
         }
         _ => {
             panic!("Unsupported interrupt with code: {}", mcause);
@@ -49,39 +64,73 @@ unsafe fn handle_interrupt(mcause: usize) {
     }
 }
 
-unsafe fn handle_exception(mcause: usize, mepc: usize, sp: usize) {
+unsafe fn handle_exception(mcause: usize, mepc: usize) {
     match mcause {
         1 => {
             // Instruction access fault
-            let mtval: usize;
-            read_machine_reg!("mtval" => mtval);
+            let mtval = cpu::tval_read();
+            let id = scheduler::cur().id();
             panic!(
-                "Instruction access fault in user prog: {:?}, mepc: 0x{:x}, mtval: 0x{:x}",
-                scheduler::cur().id(),
-                mepc,
+                "Instruction access fault in user prog: {:?}, mepc: {}, mtval: 0x{:x}",
+                id,
+                symbols::user_addr(id, mepc),
                 mtval
             );
         }
         5 => {
             // Load access fault
-            let mtval: usize;
-            read_machine_reg!("mtval" => mtval);
+            let mtval = cpu::tval_read();
+            let id = scheduler::cur().id();
             panic!(
-                "Load access fault in user prog: {:?}, mepc: 0x{:x}, mtval: 0x{:x}",
-                scheduler::cur().id(),
-                mepc,
+                "Load access fault in user prog: {:?}, mepc: {}, mtval: 0x{:x}",
+                id,
+                symbols::user_addr(id, mepc),
                 mtval
             );
         }
+        12 | 13 | 15 => {
+            // Instruction, load or store page fault
+            let mtval = cpu::tval_read();
+            let access = match mcause {
+                12 => vma::Access::Execute,
+                13 => vma::Access::Load,
+                _ => vma::Access::Store,
+            };
+            let cur = scheduler::cur();
+            if !cur.handle_page_fault(mtval, access) {
+                crate::warn!(
+                    "{:?} page fault in user prog: {:?}, mepc: {}, mtval: 0x{:x}. Terminating it",
+                    access,
+                    cur.id(),
+                    symbols::user_addr(cur.id(), mepc),
+                    mtval
+                );
+                system_calls::terminate();
+            }
+        }
+        3 => {
+            // Breakpoint, set by gdb or compiled into the user prog
+            if !gdb::handle_breakpoint() {
+                let cur = scheduler::cur();
+                crate::warn!(
+                    "Breakpoint in user prog: {:?}, mepc: {}, without gdb. Terminating it",
+                    cur.id(),
+                    symbols::user_addr(cur.id(), mepc)
+                );
+                system_calls::terminate();
+            }
+        }
         8 => {
             // Ecall from user-mode
-            let mut stack = Stack::new(sp);
-            let number = stack.a7();
-            let param_0 = stack.a0();
-            let param_1 = stack.a1();
+            let cur = scheduler::cur();
+            // Continue after the ecall instruction.
+            cur.increment_mepc();
+            let regs = cur.regs();
+            let number = regs.a7();
+            let param_0 = regs.a0();
+            let param_1 = regs.a1();
             if let Some(ret) = system_calls::syscall(number, param_0, param_1) {
-                stack.set_ret(ret);
-                stack.write();
+                cur.set_ret(ret);
             }
         }
         _ => {
@@ -0,0 +1,311 @@
+// A parser for the flattened device tree (dtb) qemu passes in a1 at boot.
+// See https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+use core::slice;
+
+const MAGIC: u32 = 0xd00d_feed;
+/// The oldest version with the layout parsed here.
+const LAST_COMP_VERSION: u32 = 16;
+
+// Tokens of the structure block
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+
+/// A device tree blob in memory. All values in it are big endian.
+pub struct Fdt {
+    data: &'static [u8],
+    structs: usize,
+    strings: usize,
+}
+
+/// An entry of the structure block.
+pub enum Token {
+    /// Start of a node with its name, e.g. `uart@10000000`. The properties of a node come before its children.
+    BeginNode(&'static str),
+    EndNode,
+    /// A property with its name and value.
+    Prop(&'static str, &'static [u8]),
+}
+
+impl Fdt {
+    /// Returns the device tree at `addr`, if there is a valid one.
+    pub unsafe fn from_addr(addr: usize) -> Option<Fdt> {
+        if addr == 0 || addr % 8 != 0 {
+            return None;
+        }
+        let header = slice::from_raw_parts(addr as *const u8, 40);
+        if be32(header, 0)? != MAGIC || be32(header, 24)? < LAST_COMP_VERSION {
+            return None;
+        }
+        let size = be32(header, 4)? as usize;
+        Some(Fdt {
+            data: slice::from_raw_parts(addr as *const u8, size),
+            structs: be32(header, 8)? as usize,
+            strings: be32(header, 12)? as usize,
+        })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the entries of the structure block in order.
+    pub fn tokens(&self) -> Tokens<'_> {
+        Tokens {
+            fdt: self,
+            pos: self.structs,
+        }
+    }
+
+    /// Returns the NUL terminated string at `offset` of the strings block.
+    fn string(&self, offset: usize) -> &'static str {
+        str_at(self.data, self.strings + offset).0
+    }
+}
+
+pub struct Tokens<'a> {
+    fdt: &'a Fdt,
+    pos: usize,
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let data = self.fdt.data;
+        loop {
+            // A truncated tree ends like a complete one.
+            let token = be32(data, self.pos)?;
+            self.pos += 4;
+            match token {
+                BEGIN_NODE => {
+                    let (name, len) = str_at(data, self.pos);
+                    self.pos = align4(self.pos + len + 1);
+                    return Some(Token::BeginNode(name));
+                }
+                END_NODE => return Some(Token::EndNode),
+                PROP => {
+                    let len = be32(data, self.pos)? as usize;
+                    let name = self.fdt.string(be32(data, self.pos + 4)? as usize);
+                    let start = self.pos + 8;
+                    let value = data.get(start..start + len)?;
+                    self.pos = align4(start + len);
+                    return Some(Token::Prop(name, value));
+                }
+                NOP => continue,
+                // END or a broken tree
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Reads the big endian u32 at `offset`. Returns None behind the end of `data`.
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Returns the NUL terminated string at `offset` and its length.
+fn str_at(data: &'static [u8], offset: usize) -> (&'static str, usize) {
+    let bytes = &data[offset.min(data.len())..];
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    (core::str::from_utf8(&bytes[..len]).unwrap_or(""), len)
+}
+
+const fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
+
+/// Reads a number of `cells` 32 bit cells from the start of `value` and returns it with the rest of `value`.
+/// Returns None if `value` is too short.
+pub fn read_cells(value: &[u8], cells: usize) -> Option<(usize, &[u8])> {
+    if cells > 2 || value.len() < 4 * cells {
+        return None;
+    }
+    let number = (0..cells).try_fold(0, |number, i| {
+        Some((number << 32) | be32(value, 4 * i)? as usize)
+    })?;
+    Some((number, &value[4 * cells..]))
+}
+
+/// Reads a string property, up to its NUL.
+pub fn read_str(value: &'static [u8]) -> &'static str {
+    str_at(value, 0).0
+}
+
+/// Returns true if the `compatible` property lists `name`.
+pub fn is_compatible(compatible: &[u8], name: &str) -> bool {
+    compatible
+        .split(|&b| b == 0)
+        .any(|entry| entry == name.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a dtb with the header, an empty memory reservation block, the structure and the strings block.
+    #[derive(Default)]
+    struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn token(&mut self, token: u32) -> &mut Self {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+        fn pad(&mut self) {
+            self.structs.resize(align4(self.structs.len()), 0);
+        }
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(PROP).token(value.len() as u32).token(offset);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+        fn end(&mut self) -> &mut Self {
+            self.token(END_NODE)
+        }
+        /// Returns the blob, 8 byte aligned like the one of qemu. It is leaked, the tokens borrow it for 'static.
+        fn build(&mut self) -> &'static mut [u8] {
+            self.token(9);
+            let structs = 56;
+            let strings = structs + self.structs.len();
+            let size = strings + self.strings.len();
+            let header = [
+                MAGIC,
+                size as u32,
+                structs as u32,
+                strings as u32,
+                40,
+                17,
+                16,
+                0,
+            ];
+            let mut data: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+            data.resize(structs, 0);
+            data.extend_from_slice(&self.structs);
+            data.extend_from_slice(&self.strings);
+            let words = Vec::leak(vec![0u64; size.div_ceil(8)]);
+            let blob = unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, size) };
+            blob.copy_from_slice(&data);
+            blob
+        }
+    }
+
+    fn parse(blob: &[u8]) -> Option<Fdt> {
+        unsafe { Fdt::from_addr(blob.as_ptr() as usize) }
+    }
+
+    /// Names the tokens, so a whole tree can be compared at once.
+    fn describe(fdt: &Fdt) -> Vec<String> {
+        fdt.tokens()
+            .map(|token| match token {
+                Token::BeginNode(name) => format!("begin {}", name),
+                Token::EndNode => "end".to_string(),
+                Token::Prop(name, value) => format!("{} {:?}", name, value),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tokens_in_order() {
+        let blob = Builder::default()
+            .begin("")
+            .prop("#address-cells", &2u32.to_be_bytes())
+            .token(NOP)
+            .begin("uart@10000000")
+            .prop("compatible", b"ns16550a\0")
+            .prop("empty", &[])
+            .end()
+            .end()
+            .build();
+        let fdt = parse(blob).unwrap();
+        assert_eq!(fdt.size(), blob.len());
+        assert_eq!(
+            describe(&fdt),
+            [
+                "begin ",
+                "#address-cells [0, 0, 0, 2]",
+                "begin uart@10000000",
+                "compatible [110, 115, 49, 54, 53, 53, 48, 97, 0]",
+                "empty []",
+                "end",
+                "end",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let blob = Builder::default().begin("").end().build();
+        assert!(parse(blob).is_some());
+        blob[3] ^= 1;
+        assert!(parse(blob).is_none(), "Wrong magic");
+        blob[3] ^= 1;
+        // last_comp_version
+        blob[27] = 15;
+        assert!(parse(blob).is_none(), "Old version");
+        assert!(unsafe { Fdt::from_addr(0) }.is_none());
+        assert!(unsafe { Fdt::from_addr(blob.as_ptr() as usize + 4) }.is_none());
+    }
+
+    #[test]
+    fn truncated_tree_ends_the_tokens() {
+        let full = Builder::default()
+            .begin("")
+            .prop("timebase-frequency", &10_000_000u32.to_be_bytes())
+            .end()
+            .build();
+        // The root node takes 8 bytes of the structure block, its property the next 16.
+        let prop_end = 56 + 8 + 16;
+        for size in 56..full.len() {
+            full[4..8].copy_from_slice(&(size as u32).to_be_bytes());
+            let fdt = parse(full).unwrap();
+            let tokens = describe(&fdt);
+            if size < prop_end {
+                assert!(tokens.len() <= 1, "{} bytes: {:?}", size, tokens);
+            }
+        }
+    }
+
+    #[test]
+    fn cells() {
+        let value = [0, 0, 0, 1, 0, 0, 0, 2, 0xff];
+        assert_eq!(read_cells(&value, 1), Some((1, &value[4..])));
+        assert_eq!(read_cells(&value, 2), Some((0x1_0000_0002, &value[8..])));
+        assert_eq!(read_cells(&value, 0), Some((0, &value[..])));
+        assert_eq!(read_cells(&value[..7], 2), None);
+        assert_eq!(read_cells(&value, 3), None);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(read_str(b"console=ttyS0\0rest"), "console=ttyS0");
+        assert_eq!(read_str(b"no nul"), "no nul");
+        assert!(is_compatible(
+            b"sifive,plic-1.0.0\0riscv,plic0\0",
+            "riscv,plic0"
+        ));
+        assert!(!is_compatible(b"riscv,plic0\0", "riscv,plic"));
+        assert!(!is_compatible(b"", "ns16550a"));
+    }
+}
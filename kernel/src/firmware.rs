@@ -0,0 +1,276 @@
+// A minimal SBI implementation in M-mode, so the kernel can run in S-mode without an external firmware.
+// `boot.S` calls [sbi_init] in M-mode, which returns to `kernel_setup` in S-mode.
+// Afterwards only the traps that are not delegated to S-mode end up here:
+// the ecalls of the kernel, the machine timer and the machine software interrupt.
+// See https://github.com/riscv-non-isa/riscv-sbi-doc
+use crate::cpu::MAX_HARTS;
+use crate::hardware::binary_struct::BinaryStruct;
+use crate::hardware::memory_mapping::MemoryMapping;
+use crate::hardware::pmp::{Permission, Pmp, Region};
+use crate::hardware::syscon;
+use crate::hardware::uart;
+use core::fmt::Write;
+use riscv_utils::*;
+
+// Extension ids
+const EXT_LEGACY_PUTCHAR: usize = 0x01;
+const EXT_LEGACY_GETCHAR: usize = 0x02;
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x5449_4d45;
+const EXT_IPI: usize = 0x0073_5049;
+const EXT_SRST: usize = 0x5352_5354;
+
+// Error codes
+const SUCCESS: isize = 0;
+const ERR_NOT_SUPPORTED: isize = -2;
+const ERR_INVALID_PARAM: isize = -3;
+
+/// Implements version 1.0 of the specification.
+const SPEC_VERSION: usize = 1 << 24;
+/// Not a registered implementation id.
+const IMPL_ID: usize = 0x5253;
+const IMPL_VERSION: usize = 1;
+
+// mcause codes
+const INTERRUPT_BIT: usize = 1 << 63;
+const MACHINE_SOFTWARE_INTERRUPT: usize = 3;
+const MACHINE_TIMER_INTERRUPT: usize = 7;
+const ECALL_FROM_S: usize = 9;
+
+// Bits of mip and mideleg
+const SSIP: usize = 1 << 1;
+const STIP: usize = 1 << 5;
+const SEIP: usize = 1 << 9;
+const MTIE: usize = 1 << 7;
+
+/// Misaligned accesses, access faults, illegal instructions, breakpoints, ecalls from U-mode and page faults.
+/// Ecalls from S-mode are the SBI calls and stay in M-mode.
+const DELEGATED_EXCEPTIONS: usize = 1 << 0
+    | 1 << 1
+    | 1 << 2
+    | 1 << 3
+    | 1 << 4
+    | 1 << 5
+    | 1 << 6
+    | 1 << 7
+    | 1 << 8
+    | 1 << 12
+    | 1 << 13
+    | 1 << 15;
+
+const STACK_SIZE: usize = 0x4000;
+const STACKS_SIZE: usize = STACK_SIZE * MAX_HARTS;
+
+/// The stacks of the trap handler, one per hart. `mscratch` points to the top of the stack of its hart while the kernel runs.
+/// They are aligned to their size, so one NAPOT pmp entry keeps the kernel out of them.
+#[repr(C, align(0x20000))]
+struct Stacks([[u8; STACK_SIZE]; MAX_HARTS]);
+
+const _: () = assert!(core::mem::align_of::<Stacks>() == STACKS_SIZE);
+
+static mut STACKS: Stacks = Stacks([[0; STACK_SIZE]; MAX_HARTS]);
+
+/// Writes to the uart directly, the console of the kernel goes through the SBI calls handled here.
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            unsafe { uart::write_byte(byte) };
+        }
+        Ok(())
+    }
+}
+
+/// Sets up M-mode of the calling hart and prepares the `mret` of `boot.S` to `kernel_setup` in S-mode.
+#[cfg_attr(not(test), no_mangle)]
+unsafe extern "C" fn sbi_init() {
+    let trap_handler = crate::asm::sbi_trap as usize;
+    write_machine_reg!(trap_handler => "mtvec");
+    let stacks = core::ptr::addr_of!(STACKS) as usize;
+    let stack_top = stacks + STACK_SIZE * (hart_id() + 1);
+    write_machine_reg!(stack_top => "mscratch");
+
+    let medeleg = DELEGATED_EXCEPTIONS;
+    write_machine_reg!(medeleg => "medeleg");
+    // The plic raises SEIP itself, the timer and software interrupts are forwarded by the handler.
+    write_machine_reg!(SSIP | STIP | SEIP => "mideleg");
+    // S-mode may read time, cycle and instret.
+    write_machine_reg!(0b111 => "mcounteren");
+
+    // No timer interrupt until the kernel asks for one.
+    MemoryMapping::new(MTIMECMP_ADDR + 8 * hart_id()).write(u64::MAX);
+    let mut mie = BinaryStruct::from(0);
+    mie.write_register_entry(MIE_MSIE);
+    write_machine_reg!(mie.get() => "mie");
+
+    // Without a matching pmp entry S-mode can't access any memory.
+    let mut pmp = Pmp::new();
+    pmp.add(
+        Region::new(stacks, stacks + STACKS_SIZE, Permission::None),
+        false,
+    );
+    pmp.add(
+        Region::new(0, usize::MAX, Permission::ReadWriteExecute),
+        false,
+    );
+    pmp.write();
+
+    // set M Previous Privilege mode to Supervisor so mret returns to the kernel in S-mode.
+    let mstatus: usize;
+    read_machine_reg!("mstatus" => mstatus);
+    let mut mstatus = BinaryStruct::from(mstatus);
+    mstatus.write_register_entry(MSTATUS_MPP_S.0);
+    mstatus.write_register_entry(MSTATUS_MPP_S.1);
+    write_machine_reg!(mstatus.get() => "mstatus");
+    let kernel_entry = crate::kernel_setup as usize;
+    write_machine_reg!(kernel_entry => "mepc");
+}
+
+/// Called by `sbi.S` with the saved registers of the trapped hart, x0 - x31.
+#[cfg_attr(not(test), no_mangle)]
+unsafe extern "C" fn sbi_handler(regs: &mut [usize; 32]) {
+    let mcause: usize;
+    read_machine_reg!("mcause" => mcause);
+    if mcause & INTERRUPT_BIT != 0 {
+        match mcause & !INTERRUPT_BIT {
+            MACHINE_TIMER_INTERRUPT => {
+                // Hand the interrupt to the kernel, the next set_timer call clears it.
+                core::arch::asm!("csrs mip, {}", "csrc mie, {}", in(reg) STIP, in(reg) MTIE);
+            }
+            MACHINE_SOFTWARE_INTERRUPT => {
+                MemoryMapping::<u32>::new(MSIP_ADDR + 4 * hart_id()).write(0);
+                core::arch::asm!("csrs mip, {}", in(reg) SSIP);
+            }
+            code => halt(format_args!(
+                "Unexpected interrupt {} in the SBI layer",
+                code
+            )),
+        }
+        return;
+    }
+    if mcause != ECALL_FROM_S {
+        let mepc: usize;
+        let mtval: usize;
+        read_machine_reg!("mepc" => mepc, "mtval" => mtval);
+        halt(format_args!(
+            "Unexpected exception {} in the SBI layer at 0x{:x}, mtval 0x{:x}",
+            mcause, mepc, mtval
+        ));
+    }
+    let (eid, fid) = (regs[17], regs[16]);
+    let (a0, a1) = (regs[10], regs[11]);
+    match eid {
+        // The legacy extensions only return a value in a0.
+        EXT_LEGACY_PUTCHAR => {
+            uart::write_byte(a0 as u8);
+            regs[10] = 0;
+        }
+        EXT_LEGACY_GETCHAR => {
+            regs[10] = match uart::try_read_byte() {
+                Some(byte) => byte as usize,
+                None => usize::MAX,
+            };
+        }
+        _ => {
+            let (error, value) = call(eid, fid, a0, a1);
+            regs[10] = error as usize;
+            regs[11] = value;
+        }
+    }
+    // Continue behind the ecall
+    let mepc: usize;
+    read_machine_reg!("mepc" => mepc);
+    write_machine_reg!(mepc + 4 => "mepc");
+}
+
+/// Executes the function `fid` of the extension `eid`. Returns the error code and the value.
+unsafe fn call(eid: usize, fid: usize, a0: usize, a1: usize) -> (isize, usize) {
+    match (eid, fid) {
+        (EXT_BASE, 0) => (SUCCESS, SPEC_VERSION),
+        (EXT_BASE, 1) => (SUCCESS, IMPL_ID),
+        (EXT_BASE, 2) => (SUCCESS, IMPL_VERSION),
+        (EXT_BASE, 3) => (SUCCESS, is_implemented(a0) as usize),
+        // mvendorid, marchid and mimpid
+        (EXT_BASE, 4) => {
+            let id: usize;
+            read_machine_reg!("mvendorid" => id);
+            (SUCCESS, id)
+        }
+        (EXT_BASE, 5) => {
+            let id: usize;
+            read_machine_reg!("marchid" => id);
+            (SUCCESS, id)
+        }
+        (EXT_BASE, 6) => {
+            let id: usize;
+            read_machine_reg!("mimpid" => id);
+            (SUCCESS, id)
+        }
+        (EXT_TIME, 0) => {
+            set_timer(a0 as u64);
+            (SUCCESS, 0)
+        }
+        (EXT_IPI, 0) => send_ipi(a0, a1),
+        (EXT_SRST, 0) => system_reset(a0, a1),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn is_implemented(eid: usize) -> bool {
+    matches!(
+        eid,
+        EXT_LEGACY_PUTCHAR | EXT_LEGACY_GETCHAR | EXT_BASE | EXT_TIME | EXT_IPI | EXT_SRST
+    )
+}
+
+fn hart_id() -> usize {
+    let hart_id: usize;
+    unsafe { read_machine_reg!("mhartid" => hart_id) };
+    hart_id
+}
+
+/// Programs the timer of the calling hart and clears its pending supervisor timer interrupt.
+unsafe fn set_timer(time: u64) {
+    MemoryMapping::new(MTIMECMP_ADDR + 8 * hart_id()).write(time);
+    core::arch::asm!("csrc mip, {}", "csrs mie, {}", in(reg) STIP, in(reg) MTIE);
+}
+
+/// Raises a software interrupt on the harts in `mask`, relative to `base`.
+/// A `base` of -1 addresses all harts.
+unsafe fn send_ipi(mask: usize, base: usize) -> (isize, usize) {
+    let (mask, base) = match base {
+        usize::MAX => (usize::MAX, 0),
+        base => (mask, base),
+    };
+    for bit in 0..usize::BITS as usize {
+        let hart = base + bit;
+        if mask & (1 << bit) == 0 || hart >= MAX_HARTS {
+            continue;
+        }
+        // The handler of the target hart turns it into a supervisor software interrupt.
+        MemoryMapping::<u32>::new(MSIP_ADDR + 4 * hart).write(1);
+    }
+    (SUCCESS, 0)
+}
+
+/// Shuts down or reboots the machine with the qemu test device.
+unsafe fn system_reset(reset_type: usize, reason: usize) -> (isize, usize) {
+    match (reset_type, reason) {
+        // shutdown with a system failure
+        (0, 1) => syscon::poweroff(1),
+        (0, _) => syscon::poweroff(0),
+        // cold and warm reboot
+        (1 | 2, _) => syscon::reboot(),
+        _ => return (ERR_INVALID_PARAM, 0),
+    }
+    halt(format_args!("The machine did not turn off"));
+}
+
+/// Prints the message and stops the hart. The panic handler can't be used, it prints with SBI calls.
+fn halt(message: core::fmt::Arguments) -> ! {
+    let _ = writeln!(Console, "{}", message);
+    loop {
+        unsafe { core::arch::asm!("wfi") };
+    }
+}
@@ -0,0 +1,902 @@
+// Stub of the gdb remote serial protocol. gdb debugs the user progs through it as the threads of one target,
+// the thread ids are the pids.
+// It is turned on by `gdb` in the boot arguments, then the kernel waits for gdb before the user progs start.
+// The stub talks over the second uart of the device tree, or over the console while the kernel waits for gdb.
+// Breakpoints replace instructions with an `ebreak`, which traps into [handle_breakpoint]. A single step puts
+// temporary breakpoints on the instructions that may run next. While a user prog is stopped, the hart polls
+// the uart holding the kernel lock, the user progs of the other harts keep running.
+// See https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+use core::fmt::{self, Write};
+
+use crate::hardware::uart::PolledUart;
+use crate::scheduler::{self, Prog};
+use crate::sync::SpinLock;
+use crate::{cpu, ipi, page, platform};
+
+/// Bytes of the longest packet, gdb learns it from qSupported.
+const PACKET_SIZE: usize = 0x400;
+const MAX_BREAKPOINTS: usize = 32;
+/// A conditional branch continues at one of two instructions.
+const MAX_STEP_BREAKPOINTS: usize = 2;
+const EBREAK: [u8; 4] = 0x0010_0073u32.to_le_bytes();
+const C_EBREAK: [u8; 2] = 0x9002u16.to_le_bytes();
+/// x0 - x31 and the pc, in the order of the target description.
+const REGS: usize = 33;
+const PC: usize = 32;
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+static STUB: SpinLock<Option<Stub>> = SpinLock::new(None);
+
+/// Turns the stub on, if the boot arguments contain `gdb`, and waits until gdb continues the user progs.
+/// Has to be called after the first user progs were created and before they start.
+pub unsafe fn init() {
+    let platform = platform::get();
+    if !platform
+        .bootargs
+        .split_ascii_whitespace()
+        .any(|arg| arg == "gdb")
+    {
+        return;
+    }
+    let port = match platform.debug_uart {
+        Some(addr) => {
+            crate::info!("Waiting for gdb on the uart at 0x{:x}", addr);
+            PolledUart::init(addr, platform.uart_clock)
+        }
+        None => {
+            crate::info!("Waiting for gdb on the console");
+            PolledUart::console()
+        }
+    };
+    let mut stub = STUB.lock();
+    let stub = stub.insert(Stub::new(port));
+    // The first user prog counts as stopped at its entry, gdb asks for the reason itself.
+    stub.stop(
+        scheduler::pids().into_iter().flatten().next().unwrap_or(0),
+        false,
+    );
+    stub.serve();
+}
+
+/// Stops the current user prog on the `ebreak` it trapped on and lets gdb debug it.
+/// Returns false, if the stub is off.
+pub unsafe fn handle_breakpoint() -> bool {
+    let mut stub = STUB.lock();
+    match stub.as_mut() {
+        Some(stub) => {
+            stub.breakpoint(scheduler::cur());
+            true
+        }
+        None => false,
+    }
+}
+
+/// An instruction replaced with an `ebreak`.
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    /// Physical address of the instruction. The user progs of an image share its code.
+    paddr: usize,
+    len: usize,
+    instruction: [u8; 4],
+}
+
+impl Breakpoint {
+    /// Replaces the instruction at `vaddr` of the user prog with an `ebreak` of `len` bytes, 2 for the compressed one.
+    unsafe fn insert(prog: Prog, vaddr: usize, len: usize) -> Option<Self> {
+        // The instructions are aligned to 2 bytes, an instruction on two pages is not supported.
+        if (len != 2 && len != 4)
+            || vaddr % 2 != 0
+            || vaddr % page::PAGE_SIZE + len > page::PAGE_SIZE
+        {
+            return None;
+        }
+        let paddr = prog.translate(vaddr)?;
+        let mut instruction = [0; 4];
+        for (i, byte) in instruction[..len].iter_mut().enumerate() {
+            *byte = ((paddr + i) as *const u8).read_volatile();
+        }
+        let breakpoint = Breakpoint {
+            paddr,
+            len,
+            instruction,
+        };
+        breakpoint.set();
+        Some(breakpoint)
+    }
+
+    /// Writes the `ebreak`.
+    unsafe fn set(&self) {
+        let code = if self.len == 2 {
+            &C_EBREAK[..]
+        } else {
+            &EBREAK[..]
+        };
+        for (i, &byte) in code.iter().enumerate() {
+            ((self.paddr + i) as *mut u8).write_volatile(byte);
+        }
+        ipi::fence_i();
+    }
+
+    /// Writes the instruction back.
+    unsafe fn remove(&self) {
+        for i in 0..self.len {
+            ((self.paddr + i) as *mut u8).write_volatile(self.instruction[i]);
+        }
+        ipi::fence_i();
+    }
+}
+
+struct Stub {
+    port: PolledUart,
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// Temporary breakpoints of a single step, on the instructions that may run next.
+    step: [Option<Breakpoint>; MAX_STEP_BREAKPOINTS],
+    /// The breakpoint removed, so the step runs the instruction under it. It is set again after the step.
+    stepped_over: Option<Breakpoint>,
+    /// The step only moves past a breakpoint, the user prog continues afterwards.
+    continue_after_step: bool,
+    /// pid of the user prog that stopped.
+    stopped: usize,
+    /// It stopped on a breakpoint of gdb.
+    swbreak: bool,
+    /// pid of the user prog whose registers and memory are accessed, selected by `Hg`.
+    thread: usize,
+    /// pid of the user prog that steps, selected by `Hc`. None for the stopped one.
+    step_thread: Option<usize>,
+}
+
+impl Stub {
+    fn new(port: PolledUart) -> Self {
+        Stub {
+            port,
+            packet: [0; PACKET_SIZE],
+            reply: Reply::new(),
+            breakpoints: [None; MAX_BREAKPOINTS],
+            step: [None; MAX_STEP_BREAKPOINTS],
+            stepped_over: None,
+            continue_after_step: false,
+            stopped: 0,
+            swbreak: false,
+            thread: 0,
+            step_thread: None,
+        }
+    }
+
+    fn stop(&mut self, pid: usize, swbreak: bool) {
+        self.stopped = pid;
+        self.thread = pid;
+        self.step_thread = None;
+        self.swbreak = swbreak;
+    }
+
+    /// Handles the trap of an `ebreak` in `prog`.
+    unsafe fn breakpoint(&mut self, prog: Prog) {
+        let pc = prog.pc();
+        let paddr = prog.translate(pc);
+        let hit = |breakpoint: &Option<Breakpoint>| {
+            breakpoint.is_some_and(|breakpoint| Some(breakpoint.paddr) == paddr)
+        };
+        let is_step = self.step.iter().any(hit);
+        let is_breakpoint = self.breakpoints.iter().any(hit);
+        if is_step {
+            self.end_step();
+            if self.continue_after_step && !is_breakpoint {
+                return;
+            }
+        } else if !is_breakpoint {
+            // An ebreak of the user prog itself, it is skipped like gdb expects.
+            match read_instruction(prog, pc) {
+                Some(instruction) => prog.set_pc(pc + instruction_len(instruction)),
+                None => prog.set_pc(pc + 4),
+            }
+        }
+        self.stop(prog.pid(), is_breakpoint);
+        self.reply.clear();
+        self.write_stop_reason();
+        self.send();
+        self.serve();
+    }
+
+    /// Answers the packets of gdb, until it resumes the user progs.
+    unsafe fn serve(&mut self) {
+        loop {
+            let len = self.receive();
+            // A copy, the handlers change the stub.
+            let packet = self.packet;
+            self.reply.clear();
+            if self.handle(&packet[..len]) {
+                return;
+            }
+            self.send();
+        }
+    }
+
+    /// Handles a packet and writes the reply. Returns true, if the user progs are resumed.
+    /// Commands the stub does not know get an empty reply.
+    unsafe fn handle(&mut self, packet: &[u8]) -> bool {
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return false,
+        };
+        let result = match command {
+            b'?' => {
+                self.write_stop_reason();
+                Some(())
+            }
+            b'g' => self.read_registers(),
+            b'G' => self.write_registers(args),
+            b'p' => self.read_register(args),
+            b'P' => self.write_register(args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'c' | b's' => match self.resume(args, command == b's') {
+                Some(()) => return true,
+                None => None,
+            },
+            b'H' => self.select_thread(args),
+            b'T' => parse_hex(args)
+                .and_then(scheduler::find)
+                .map(|_| self.reply.push_str("OK")),
+            b'Z' | b'z' => self.change_breakpoint(command == b'Z', args),
+            b'q' => {
+                self.query(args);
+                Some(())
+            }
+            b'D' => {
+                self.detach();
+                self.reply.push_str("OK");
+                self.send();
+                return true;
+            }
+            b'k' => crate::shutdown(0),
+            _ => Some(()),
+        };
+        if result.is_none() {
+            self.reply.clear();
+            self.reply.push_str("E01");
+        }
+        false
+    }
+
+    /// Waits for the next packet and acknowledges it. Returns the length of its data in `packet`.
+    unsafe fn receive(&mut self) -> usize {
+        loop {
+            // Everything outside a packet, like acknowledgements or a Ctrl-C, is skipped.
+            while self.port.read() != b'$' {}
+            let (mut len, mut sum, mut overflow) = (0, 0u8, false);
+            loop {
+                let byte = self.port.read();
+                if byte == b'#' {
+                    break;
+                }
+                match self.packet.get_mut(len) {
+                    Some(slot) => *slot = byte,
+                    None => overflow = true,
+                }
+                len += 1;
+                sum = sum.wrapping_add(byte);
+            }
+            let checksum = [self.port.read(), self.port.read()];
+            if !overflow && parse_hex(&checksum) == Some(sum as usize) {
+                self.port.write(b'+');
+                return len;
+            }
+            self.port.write(b'-');
+        }
+    }
+
+    /// Sends the reply, until gdb acknowledges it.
+    unsafe fn send(&mut self) {
+        loop {
+            self.port.write(b'$');
+            let mut sum = 0u8;
+            for &byte in self.reply.bytes() {
+                self.port.write(byte);
+                sum = sum.wrapping_add(byte);
+            }
+            self.port.write(b'#');
+            self.port.write(HEX[(sum >> 4) as usize]);
+            self.port.write(HEX[(sum & 0xf) as usize]);
+            loop {
+                match self.port.read() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn write_stop_reason(&mut self) {
+        let swbreak = if self.swbreak { "swbreak:;" } else { "" };
+        // Signal 5 is SIGTRAP
+        write!(self.reply, "T05thread:{:x};{}", self.stopped, swbreak).ok();
+    }
+
+    /// The user prog selected by `Hg`.
+    fn thread(&self) -> Option<Prog> {
+        scheduler::find(self.thread)
+    }
+
+    fn read_registers(&mut self) -> Option<()> {
+        for reg in registers(self.thread()?) {
+            self.reply.push_hex(&reg.to_le_bytes());
+        }
+        Some(())
+    }
+
+    fn write_registers(&mut self, args: &[u8]) -> Option<()> {
+        let prog = self.thread()?;
+        if args.len() != REGS * 16 {
+            return None;
+        }
+        let mut regs = [0; REGS];
+        for (reg, hex) in regs.iter_mut().zip(args.chunks(16)) {
+            *reg = parse_le(hex)?;
+        }
+        set_registers(prog, &regs);
+        self.reply.push_str("OK");
+        Some(())
+    }
+
+    fn read_register(&mut self, args: &[u8]) -> Option<()> {
+        let reg = registers(self.thread()?).get(parse_hex(args)?).copied()?;
+        self.reply.push_hex(&reg.to_le_bytes());
+        Some(())
+    }
+
+    fn write_register(&mut self, args: &[u8]) -> Option<()> {
+        let prog = self.thread()?;
+        let (idx, value) = split(args, b'=')?;
+        let mut regs = registers(prog);
+        *regs.get_mut(parse_hex(idx)?)? = parse_le(value)?;
+        set_registers(prog, &regs);
+        self.reply.push_str("OK");
+        Some(())
+    }
+
+    /// Reads as many bytes as are mapped, at least one.
+    fn read_memory(&mut self, args: &[u8]) -> Option<()> {
+        let prog = self.thread()?;
+        let (addr, len) = split(args, b',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        // A range behind the end of the address space is an error, not a wrap around.
+        addr.checked_add(len)?;
+        for i in 0..len.min(PACKET_SIZE / 2) {
+            match read_byte(prog, addr + i) {
+                Some(byte) => self.reply.push_hex(&[byte]),
+                None if i == 0 => return None,
+                None => break,
+            }
+        }
+        Some(())
+    }
+
+    /// Writes the memory the user prog may write itself. The code is changed with breakpoints only.
+    unsafe fn write_memory(&mut self, args: &[u8]) -> Option<()> {
+        let prog = self.thread()?;
+        let (addr, rest) = split(args, b',')?;
+        let (len, data) = split(rest, b':')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        if len.checked_mul(2)? != data.len() {
+            return None;
+        }
+        // Checked before the first byte is written.
+        addr.checked_add(len)?;
+        for (i, hex) in data.chunks(2).enumerate() {
+            let byte = parse_hex(hex)? as u8;
+            let paddr = prog.with_space(|space| space.translate_write(addr + i))?;
+            (paddr as *mut u8).write_volatile(byte);
+        }
+        self.reply.push_str("OK");
+        Some(())
+    }
+
+    /// Continues or steps the user progs, at the address in `args` if there is one.
+    unsafe fn resume(&mut self, args: &[u8], step: bool) -> Option<()> {
+        let pid = match self.step_thread {
+            Some(pid) if step => pid,
+            _ => self.stopped,
+        };
+        // The user prog may have ended, the others continue anyway.
+        let prog = match scheduler::find(pid) {
+            Some(prog) => prog,
+            None => return Some(()),
+        };
+        if !args.is_empty() {
+            prog.set_pc(parse_hex(args)?);
+        }
+        // A user prog continuing on a breakpoint steps past it first.
+        if step || self.find_breakpoint(prog, prog.pc()).is_some() {
+            self.start_step(prog)?;
+            self.continue_after_step = !step;
+        }
+        Some(())
+    }
+
+    /// Puts the temporary breakpoints on the instructions that may run after the one at the pc of `prog`.
+    unsafe fn start_step(&mut self, prog: Prog) -> Option<()> {
+        let pc = prog.pc();
+        if let Some(idx) = self.find_breakpoint(prog, pc) {
+            let breakpoint = self.breakpoints[idx].take()?;
+            breakpoint.remove();
+            self.stepped_over = Some(breakpoint);
+        }
+        let successors = match read_instruction(prog, pc) {
+            Some(instruction) => successors(instruction, pc, &prog.regs().regs),
+            None => [None; MAX_STEP_BREAKPOINTS],
+        };
+        for (i, next) in successors.into_iter().enumerate() {
+            let next = match next {
+                Some(next) if !successors[..i].contains(&Some(next)) => next,
+                _ => continue,
+            };
+            // The compressed ebreak fits on every instruction.
+            match Breakpoint::insert(prog, next, 2) {
+                Some(breakpoint) => self.step[i] = Some(breakpoint),
+                None => {
+                    self.end_step();
+                    return None;
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Removes the temporary breakpoints and sets the one the step moved past again.
+    unsafe fn end_step(&mut self) {
+        // In reverse, a later one may lie on an earlier one.
+        for breakpoint in self.step.iter_mut().rev() {
+            if let Some(breakpoint) = breakpoint.take() {
+                breakpoint.remove();
+            }
+        }
+        if let Some(breakpoint) = self.stepped_over.take() {
+            // Its slot is still free, gdb can't change the breakpoints during the step.
+            breakpoint.set();
+            if let Some(slot) = self.breakpoints.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(breakpoint);
+            }
+        }
+    }
+
+    fn find_breakpoint(&self, prog: Prog, vaddr: usize) -> Option<usize> {
+        let paddr = prog.translate(vaddr)?;
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| breakpoint.is_some_and(|b| b.paddr == paddr))
+    }
+
+    /// Inserts or removes a software breakpoint, `args` are `type,addr,kind`.
+    /// The other types get an empty reply, gdb does not use them then.
+    unsafe fn change_breakpoint(&mut self, insert: bool, args: &[u8]) -> Option<()> {
+        let (kind, rest) = split(args, b',')?;
+        if kind != b"0" {
+            return Some(());
+        }
+        let prog = self.thread()?;
+        let (addr, len) = split(rest, b',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        let existing = self.find_breakpoint(prog, addr);
+        if insert && existing.is_none() {
+            let slot = self.breakpoints.iter().position(Option::is_none)?;
+            self.breakpoints[slot] = Some(Breakpoint::insert(prog, addr, len)?);
+        } else if !insert {
+            if let Some(breakpoint) = existing.and_then(|idx| self.breakpoints[idx].take()) {
+                breakpoint.remove();
+            }
+        }
+        self.reply.push_str("OK");
+        Some(())
+    }
+
+    /// Selects the user prog of the register and memory accesses (`Hg`) or of the steps (`Hc`).
+    /// 0 and -1 stand for any, the stopped one is taken then.
+    fn select_thread(&mut self, args: &[u8]) -> Option<()> {
+        let (&op, id) = args.split_first()?;
+        let pid = match id {
+            b"0" | b"-1" => None,
+            id => Some(scheduler::find(parse_hex(id)?)?.pid()),
+        };
+        match op {
+            b'g' => self.thread = pid.unwrap_or(self.stopped),
+            b'c' => self.step_thread = pid,
+            _ => return None,
+        }
+        self.reply.push_str("OK");
+        Some(())
+    }
+
+    fn query(&mut self, args: &[u8]) {
+        if args.starts_with(b"Supported") {
+            write!(
+                self.reply,
+                "PacketSize={:x};swbreak+;qXfer:features:read+",
+                PACKET_SIZE
+            )
+            .ok();
+        } else if args == b"Attached" {
+            // gdb detaches instead of killing the user progs, when it quits.
+            self.reply.push_str("1");
+        } else if args == b"C" {
+            write!(self.reply, "QC{:x}", self.stopped).ok();
+        } else if args == b"fThreadInfo" {
+            self.reply.push_str("m");
+            for (i, pid) in scheduler::pids().into_iter().flatten().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                write!(self.reply, "{}{:x}", separator, pid).ok();
+            }
+        } else if args == b"sThreadInfo" {
+            self.reply.push_str("l");
+        } else if let Some(id) = args.strip_prefix(b"ThreadExtraInfo,") {
+            if let Some(prog) = parse_hex(id).and_then(scheduler::find) {
+                let forked = if prog.is_forked() { ", forked" } else { "" };
+                write!(
+                    HexWriter(&mut self.reply),
+                    "{}{}",
+                    prog.id().image(),
+                    forked
+                )
+                .ok();
+            }
+        } else if let Some(range) = args.strip_prefix(b"Xfer:features:read:target.xml:") {
+            if let Some((offset, len)) =
+                split(range, b',').and_then(|(o, l)| Some((parse_hex(o)?, parse_hex(l)?)))
+            {
+                self.write_target(offset, len);
+            }
+        }
+    }
+
+    /// Writes `len` bytes of the target description from `offset` on, after `m` if more follow or `l`.
+    fn write_target(&mut self, offset: usize, len: usize) {
+        self.reply.push_str("l");
+        let mut window = Window {
+            reply: &mut self.reply,
+            skip: offset,
+            left: len.min(PACKET_SIZE - 1),
+            more: false,
+        };
+        write!(
+            window,
+            "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+             <target version=\"1.0\"><architecture>riscv:rv64</architecture>\
+             <feature name=\"org.gnu.gdb.riscv.cpu\">"
+        )
+        .ok();
+        for (regnum, name) in cpu::REG_NAMES.iter().enumerate() {
+            write!(
+                window,
+                "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>",
+                name, regnum
+            )
+            .ok();
+        }
+        write!(
+            window,
+            "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/></feature></target>",
+            PC
+        )
+        .ok();
+        if window.more {
+            self.reply.buf[0] = b'm';
+        }
+    }
+
+    /// Removes the breakpoints, the user progs run on without gdb.
+    unsafe fn detach(&mut self) {
+        for breakpoint in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = breakpoint.take() {
+                breakpoint.remove();
+            }
+        }
+    }
+}
+
+/// Returns x0 - x31 and the pc of the user prog.
+fn registers(prog: Prog) -> [usize; REGS] {
+    let mut regs = [0; REGS];
+    regs[..32].copy_from_slice(&prog.regs().regs);
+    regs[0] = 0;
+    regs[PC] = prog.pc();
+    regs
+}
+
+fn set_registers(prog: Prog, regs: &[usize; REGS]) {
+    let mut saved = [0; 32];
+    saved.copy_from_slice(&regs[..32]);
+    prog.set_regs(saved);
+    prog.set_pc(regs[PC]);
+}
+
+fn read_byte(prog: Prog, vaddr: usize) -> Option<u8> {
+    let paddr = prog.translate_read(vaddr)?;
+    Some(unsafe { (paddr as *const u8).read_volatile() })
+}
+
+/// Reads the instruction at `vaddr`, 2 or 4 bytes long.
+fn read_instruction(prog: Prog, vaddr: usize) -> Option<u32> {
+    let byte = |offset| read_byte(prog, vaddr.checked_add(offset)?);
+    let half = |offset| Some(byte(offset)? as u32 | (byte(offset + 1)? as u32) << 8);
+    let low = half(0)?;
+    if instruction_len(low) == 2 {
+        return Some(low);
+    }
+    Some(low | half(2)? << 16)
+}
+
+/// The lowest two bits are set in all instructions, except the compressed ones.
+fn instruction_len(instruction: u32) -> usize {
+    if instruction & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Returns the addresses the instruction at `pc` may continue at: the next instruction,
+/// the target of a jump, or both for a branch.
+fn successors(
+    instruction: u32,
+    pc: usize,
+    regs: &[usize; 32],
+) -> [Option<usize>; MAX_STEP_BREAKPOINTS] {
+    let next = pc.wrapping_add(instruction_len(instruction));
+    let bits = |from: u32, len: u32| ((instruction >> from) & ((1 << len) - 1)) as usize;
+    // x0 is not saved, it is always 0.
+    let reg = |idx: usize| if idx == 0 { 0 } else { regs[idx] };
+    if instruction_len(instruction) == 4 {
+        match bits(0, 7) {
+            // jal
+            0b110_1111 => {
+                let offset =
+                    bits(31, 1) << 20 | bits(21, 10) << 1 | bits(20, 1) << 11 | bits(12, 8) << 12;
+                [Some(pc.wrapping_add(sign_extend(offset, 21))), None]
+            }
+            // jalr
+            0b110_0111 => {
+                let target = reg(bits(15, 5)).wrapping_add(sign_extend(bits(20, 12), 12));
+                [Some(target & !1), None]
+            }
+            // beq, bne, blt, bge, bltu, bgeu
+            0b110_0011 => {
+                let offset =
+                    bits(31, 1) << 12 | bits(25, 6) << 5 | bits(8, 4) << 1 | bits(7, 1) << 11;
+                [Some(next), Some(pc.wrapping_add(sign_extend(offset, 13)))]
+            }
+            _ => [Some(next), None],
+        }
+    } else {
+        match (bits(0, 2), bits(13, 3)) {
+            // c.j
+            (0b01, 0b101) => {
+                let offset = bits(12, 1) << 11
+                    | bits(11, 1) << 4
+                    | bits(9, 2) << 8
+                    | bits(8, 1) << 10
+                    | bits(7, 1) << 6
+                    | bits(6, 1) << 7
+                    | bits(3, 3) << 1
+                    | bits(2, 1) << 5;
+                [Some(pc.wrapping_add(sign_extend(offset, 12))), None]
+            }
+            // c.beqz, c.bnez
+            (0b01, 0b110 | 0b111) => {
+                let offset = bits(12, 1) << 8
+                    | bits(10, 2) << 3
+                    | bits(5, 2) << 6
+                    | bits(3, 2) << 1
+                    | bits(2, 1) << 5;
+                [Some(next), Some(pc.wrapping_add(sign_extend(offset, 9)))]
+            }
+            // c.jr, c.jalr
+            (0b10, 0b100) if bits(7, 5) != 0 && bits(2, 5) == 0 => {
+                [Some(reg(bits(7, 5)) & !1), None]
+            }
+            _ => [Some(next), None],
+        }
+    }
+}
+
+/// Extends the sign of the lowest `bits` of `value`.
+fn sign_extend(value: usize, bits: u32) -> usize {
+    let shift = usize::BITS - bits;
+    (((value << shift) as isize) >> shift) as usize
+}
+
+/// Parses a hex number of gdb.
+fn parse_hex(hex: &[u8]) -> Option<usize> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    let hex = core::str::from_utf8(hex).ok()?;
+    usize::from_str_radix(hex, 16).ok()
+}
+
+/// Parses a register value, gdb sends its bytes in the order of the target.
+fn parse_le(hex: &[u8]) -> Option<usize> {
+    if hex.len() != 16 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    for (byte, hex) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = parse_hex(hex)? as u8;
+    }
+    Some(usize::from_le_bytes(bytes))
+}
+
+/// Splits `args` at the first `separator`.
+fn split(args: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let pos = args.iter().position(|&byte| byte == separator)?;
+    Some((&args[..pos], &args[pos + 1..]))
+}
+
+/// The data of the next packet to gdb. What does not fit is dropped.
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    const fn new() -> Self {
+        Reply {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn push(&mut self, byte: u8) {
+        if let Some(slot) = self.buf.get_mut(self.len) {
+            *slot = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(HEX[(byte >> 4) as usize]);
+            self.push(HEX[(byte & 0xf) as usize]);
+        }
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+/// Writes the text as hex, e.g. for qThreadExtraInfo.
+struct HexWriter<'a>(&'a mut Reply);
+
+impl Write for HexWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push_hex(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Keeps the `left` bytes of a text after the first `skip`, gdb reads long documents in parts.
+struct Window<'a> {
+    reply: &'a mut Reply,
+    skip: usize,
+    left: usize,
+    /// Bytes after the window were dropped.
+    more: bool,
+}
+
+impl Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.skip > 0 {
+                self.skip -= 1;
+            } else if self.left > 0 {
+                self.reply.push(byte);
+                self.left -= 1;
+            } else {
+                self.more = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address of the tested instruction.
+    const AT: usize = 0x1000;
+
+    fn targets(instruction: u32, regs: &[usize; 32]) -> [Option<usize>; MAX_STEP_BREAKPOINTS] {
+        successors(instruction, AT, regs)
+    }
+
+    #[test]
+    fn sign_extension() {
+        assert_eq!(sign_extend(0x7ff, 12), 0x7ff);
+        assert_eq!(sign_extend(0x800, 12), (-0x800isize) as usize);
+        assert_eq!(sign_extend(0xfff, 12), usize::MAX);
+        // Bits above the sign are dropped.
+        assert_eq!(sign_extend(0x1_0004, 13), 4);
+        assert_eq!(sign_extend(usize::MAX, 64), usize::MAX);
+    }
+
+    #[test]
+    fn plain_instructions_continue() {
+        let regs = [0; 32];
+        // addi a0, a0, 1
+        assert_eq!(targets(0x0015_0513, &regs), [Some(AT + 4), None]);
+        // c.addi a0, 1
+        assert_eq!(targets(0x0505, &regs), [Some(AT + 2), None]);
+        // ecall
+        assert_eq!(targets(0x0000_0073, &regs), [Some(AT + 4), None]);
+    }
+
+    #[test]
+    fn jumps() {
+        let mut regs = [0; 32];
+        regs[1] = 0x3000;
+        regs[10] = 0x2001;
+        // jal ra, +0x800
+        assert_eq!(targets(0x0010_00ef, &regs), [Some(AT + 0x800), None]);
+        // j -4
+        assert_eq!(targets(0xffdf_f06f, &regs), [Some(AT - 4), None]);
+        // ret, the lowest bit of the target is cleared
+        assert_eq!(targets(0x0000_8067, &regs), [Some(0x3000), None]);
+        // jalr ra, -3(a0)
+        assert_eq!(targets(0xffd5_00e7, &regs), [Some(0x1ffe), None]);
+        // jalr zero, 16(zero), x0 reads as 0 whatever is in the slot
+        regs[0] = 0xdead;
+        assert_eq!(targets(0x0100_0067, &regs), [Some(0x10), None]);
+        // c.j -2
+        assert_eq!(targets(0xbffd, &regs), [Some(AT - 2), None]);
+        // c.j +0x7fe
+        assert_eq!(targets(0xaffd, &regs), [Some(AT + 0x7fe), None]);
+        // c.jr ra
+        assert_eq!(targets(0x8082, &regs), [Some(0x3000), None]);
+    }
+
+    #[test]
+    fn branches_take_both() {
+        let regs = [0; 32];
+        // beq a0, a1, -8
+        assert_eq!(targets(0xfeb5_0ce3, &regs), [Some(AT + 4), Some(AT - 8)]);
+        // bne a0, a1, +0x7fe
+        assert_eq!(
+            targets(0x7eb5_1f63, &regs),
+            [Some(AT + 4), Some(AT + 0x7fe)]
+        );
+        // c.beqz s0, -4
+        assert_eq!(targets(0xdc75, &regs), [Some(AT + 2), Some(AT - 4)]);
+        // c.bnez s1, +0xfe
+        assert_eq!(targets(0xecfd, &regs), [Some(AT + 2), Some(AT + 0xfe)]);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex(b"80001234"), Some(0x8000_1234));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"12345678123456781"), None);
+        assert_eq!(parse_hex(b"xyz"), None);
+        assert_eq!(parse_le(b"3412000000000000"), Some(0x1234));
+        assert_eq!(split(b"m1000,4", b','), Some((&b"m1000"[..], &b"4"[..])));
+    }
+}
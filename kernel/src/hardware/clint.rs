@@ -1,15 +1,69 @@
+#[cfg(not(feature = "sbi"))]
 use super::memory_mapping::MemoryMapping;
+use crate::{perf, platform};
+#[cfg(not(feature = "sbi"))]
 use riscv_utils::*;
 
-const TIMER_DURATION: u64 = 10000000; //QEMU 10Mhz -> 1s
+/// Offsets of the registers of hart 0 from the base address found by [platform::init].
+/// The compare register of hart n follows at `8 * n`.
+#[cfg(not(feature = "sbi"))]
+const MTIMECMP_OFFSET: usize = MTIMECMP_ADDR - MSIP_ADDR;
+#[cfg(not(feature = "sbi"))]
+const MTIME_OFFSET: usize = MTIME_ADDR - MSIP_ADDR;
 
+/// One second, the timebase is 10 MHz on qemu. While profiling it is a tick of the sampler.
+fn timer_duration() -> u64 {
+    platform::get().timebase / perf::ticks_per_slice()
+}
+
+/// Returns the `time` counter, it counts with the timebase of the platform since boot.
+pub unsafe fn time() -> u64 {
+    #[cfg(not(feature = "sbi"))]
+    return MemoryMapping::new(platform::get().clint + MTIME_OFFSET).read();
+    #[cfg(feature = "sbi")]
+    {
+        let time: u64;
+        core::arch::asm!("rdtime {}", out(reg) time);
+        time
+    }
+}
+
+/// Sets the next timer interrupt of the current hart. In S-mode the firmware programs the clint.
 pub unsafe fn set_time_cmp() {
-    let mut mtimecmp = MemoryMapping::new(MTIMECMP_ADDR);
-    let mtime: u64 = MemoryMapping::new(MTIME_ADDR).read();
-    mtimecmp.write(mtime + TIMER_DURATION);
+    #[cfg(not(feature = "sbi"))]
+    {
+        let clint = platform::get().clint;
+        let mut mtimecmp = MemoryMapping::new(clint + MTIMECMP_OFFSET + 8 * crate::cpu::hart_id());
+        mtimecmp.write(time() + timer_duration());
+    }
+    #[cfg(feature = "sbi")]
+    crate::sbi::set_timer(time() + timer_duration());
+}
+
+/// Raises the software interrupt of `hart`. In S-mode the firmware writes its msip register.
+pub unsafe fn send_soft(hart: usize) {
+    #[cfg(not(feature = "sbi"))]
+    MemoryMapping::<u32>::new(platform::get().clint + 4 * hart).write(1);
+    #[cfg(feature = "sbi")]
+    crate::sbi::send_ipi(1, hart);
+}
+
+/// Clears the pending software interrupt of the current hart.
+pub unsafe fn clear_soft() {
+    #[cfg(not(feature = "sbi"))]
+    MemoryMapping::<u32>::new(platform::get().clint + 4 * crate::cpu::hart_id()).write(0);
+    // The firmware sets the pending bit of S-mode, the kernel clears it.
+    #[cfg(feature = "sbi")]
+    core::arch::asm!("csrc sip, {}", in(reg) 1usize << crate::cpu::SOFTWARE_INTERRUPT);
 }
 
 pub unsafe fn init() {
-    let mut mtimecmp = MemoryMapping::new(MTIMECMP_ADDR);
-    mtimecmp.write(u64::MAX);
+    #[cfg(not(feature = "sbi"))]
+    {
+        let mut mtimecmp =
+            MemoryMapping::new(platform::get().clint + MTIMECMP_OFFSET + 8 * crate::cpu::hart_id());
+        mtimecmp.write(u64::MAX);
+    }
+    #[cfg(feature = "sbi")]
+    crate::sbi::set_timer(u64::MAX);
 }
@@ -1,6 +1,8 @@
 pub struct MemoryMapping<T> {
     val: *mut T,
 }
+/// The registers are reachable from every hart.
+unsafe impl<T> Send for MemoryMapping<T> {}
 impl<T> MemoryMapping<T> {
     pub const fn new(address: usize) -> Self {
         MemoryMapping {
@@ -1,45 +1,155 @@
-use super::{binary_struct::BinaryStruct, memory_mapping::MemoryMapping};
+// Driver of the platform-level interrupt controller, which routes the interrupts of the devices to the harts.
+// A device registers a handler for its source with [register], [dispatch] calls it when the source is claimed.
+// See https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+use super::memory_mapping::MemoryMapping;
+use crate::sync::SpinLock;
+use crate::{cpu, platform};
 use riscv_utils::*;
 
-/// Interrupt request.
-pub enum IRQ {
-    Uart = 10,
+/// Every hart has a context for M-mode and one for S-mode, the kernel uses the one of the mode it runs in.
+const CONTEXTS_PER_HART: usize = 2;
+const MODE_CONTEXT: usize = cfg!(feature = "sbi") as usize;
+/// Offsets from the base address found by [platform::init], see `riscv_utils::reg` for the layout.
+const PRIORITY_OFFSET: usize = PRIORITY_BASE_ADDR - PLIC_MEMORY_MAP_BASE;
+pub const ENABLE_OFFSET: usize = ENABLE_ADDR - PLIC_MEMORY_MAP_BASE;
+/// Offset of the threshold register of context 0, the claim register follows it.
+pub const CONTEXT_OFFSET: usize = THRESHOLD_ADDR_C0 - PLIC_MEMORY_MAP_BASE;
+const CLAIM_OFFSET: usize = CLAIM_COMP_ADDR_C0 - THRESHOLD_ADDR_C0;
+/// Distance of the enable bits of two contexts.
+const ENABLE_STRIDE: usize = 0x80;
+/// Distance of the threshold and claim registers of two contexts.
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// Highest source id, 0 is no source.
+pub const MAX_SOURCE: usize = 1023;
+/// Highest priority of qemu virt. A source with priority 0 never interrupts.
+pub const MAX_PRIORITY: u32 = 7;
+
+/// Interrupt source of the uart of qemu virt.
+pub const UART_SOURCE: usize = 10;
+
+/// Handles the interrupt of a device, called with the claimed source id.
+pub type Handler = unsafe fn(source: usize);
+
+static HANDLERS: SpinLock<[Option<Handler>; MAX_SOURCE + 1]> =
+    SpinLock::new([None; MAX_SOURCE + 1]);
+
+/// Sets up the context of the current hart: every source with a handler is enabled and every priority above 0 let through.
+/// Neither is reset by the hardware, so every hart calls it, after the boot hart registered the handlers.
+pub unsafe fn init_hart() {
+    let handlers = HANDLERS.lock();
+    for (word, sources) in handlers.chunks(32).enumerate() {
+        let bits = sources
+            .iter()
+            .enumerate()
+            .filter(|(_, handler)| handler.is_some())
+            .fold(0u32, |bits, (bit, _)| bits | 1 << bit);
+        MemoryMapping::new(enable_addr() + 4 * word).write(bits);
+    }
+    set_threshold(0);
+}
+
+/// Calls `handler` for the interrupts of `source`, sets its `priority` and enables it for the current hart.
+/// The harts set up afterwards enable it in [init_hart].
+pub unsafe fn register(source: usize, priority: u32, handler: Handler) {
+    assert_source(source);
+    HANDLERS.lock()[source] = Some(handler);
+    set_priority(source, priority);
+    enable(source);
+}
+
+/// Disables `source` for the current hart and removes its handler.
+#[allow(dead_code)]
+pub unsafe fn unregister(source: usize) {
+    assert_source(source);
+    disable(source);
+    HANDLERS.lock()[source] = None;
+}
+
+/// Sets the priority of `source`, it applies to every hart.
+pub unsafe fn set_priority(source: usize, priority: u32) {
+    assert_source(source);
+    assert!(
+        priority <= MAX_PRIORITY,
+        "Invalid plic priority: {}",
+        priority
+    );
+    MemoryMapping::new(platform::get().plic + PRIORITY_OFFSET + 4 * source).write(priority);
+}
+
+/// Lets the interrupts of `source` through to the current hart.
+pub unsafe fn enable(source: usize) {
+    let (word, bit) = enable_word_bit(source);
+    let mut enable = MemoryMapping::new(word);
+    let bits: u32 = enable.read();
+    enable.write(bits | 1 << bit);
+}
+
+/// Keeps the interrupts of `source` away from the current hart.
+pub unsafe fn disable(source: usize) {
+    let (word, bit) = enable_word_bit(source);
+    let mut enable = MemoryMapping::new(word);
+    let bits: u32 = enable.read();
+    enable.write(bits & !(1 << bit));
+}
+
+/// Ignores the interrupts with a priority up to `threshold` on the current hart.
+pub unsafe fn set_threshold(threshold: u32) {
+    MemoryMapping::new(get_context_addr()).write(threshold);
+}
+
+/// Claims the highest pending interrupt of the current hart. Returns None, if there is none.
+pub unsafe fn claim() -> Option<usize> {
+    let source: u32 = MemoryMapping::new(get_context_addr() + CLAIM_OFFSET).read();
+    match source {
+        0 => None,
+        source => Some(source as usize),
+    }
+}
+
+/// Tells the plic the interrupt of `source` is handled, it is not raised again before.
+pub unsafe fn complete(source: usize) {
+    MemoryMapping::new(get_context_addr() + CLAIM_OFFSET).write(source as u32);
 }
 
-pub unsafe fn init() {
-    let uart_priority_addr = get_priority_addr(IRQ::Uart);
-    MemoryMapping::new(uart_priority_addr).write(5);
-    // Enable in context.
-    let mut enable_c0 = [BinaryStruct::from(0u32); 32];
-    let (uart_idx, uart_bit) = bin32_idx_pos(IRQ::Uart);
-    enable_c0[uart_idx].at(uart_bit, true);
-    let enable_addr = get_enable_addr(uart_idx);
-    MemoryMapping::new(enable_addr).write(enable_c0[uart_idx].get());
-    // Set thresholds for context.
-    MemoryMapping::new(THRESHOLD_ADDR_C0).write(0u32);
+/// Claims the pending interrupts of the current hart and calls their handlers.
+pub unsafe fn dispatch() {
+    while let Some(source) = claim() {
+        // Copied out, so a handler may register others.
+        let handler = HANDLERS.lock()[source];
+        match handler {
+            Some(handler) => handler(source),
+            None => panic!("Unknown plic interrupt request: {}", source),
+        }
+        complete(source);
+    }
 }
 
-pub unsafe fn read_claim() -> IRQ {
-    let claim: u32 = MemoryMapping::new(CLAIM_COMP_ADDR_C0).read();
-    let claim = claim as usize;
-    crate::enum_matching!(claim: IRQ::Uart);
-    panic!("Unknown plic interrupt request: {}", claim);
+fn assert_source(source: usize) {
+    assert!(
+        source > 0 && source <= MAX_SOURCE,
+        "Invalid plic source: {}",
+        source
+    );
 }
 
-pub unsafe fn write_complete(irq: IRQ) {
-    MemoryMapping::new(CLAIM_COMP_ADDR_C0).write(irq as u32);
+/// Returns the address of the enable word of `source` in the context of the current hart and its bit in it.
+fn enable_word_bit(source: usize) -> (usize, usize) {
+    assert_source(source);
+    (enable_addr() + 4 * (source / 32), source % 32)
 }
 
-fn get_priority_addr(irq: IRQ) -> usize {
-    PRIORITY_BASE_ADDR + 4 * irq as usize
+/// Returns the address of the enable bits of the context of the current hart.
+fn enable_addr() -> usize {
+    platform::get().plic + ENABLE_OFFSET + context() * ENABLE_STRIDE
 }
 
-/// Returns the (index, pos) of an irq if every bit is used as an id for an irq.
-fn bin32_idx_pos(irq: IRQ) -> (usize, usize) {
-    let irq = irq as usize;
-    (irq / 32, irq % 32)
+/// Returns the context of the current hart.
+fn context() -> usize {
+    CONTEXTS_PER_HART * cpu::hart_id() + MODE_CONTEXT
 }
 
-fn get_enable_addr(idx: usize) -> usize {
-    ENABLE_ADDR + 4 * idx
+/// Returns the address of the threshold register of the context.
+fn get_context_addr() -> usize {
+    platform::get().plic + CONTEXT_OFFSET + context() * CONTEXT_STRIDE
 }
@@ -1,51 +1,239 @@
+use crate::sync::Once;
 use riscv_utils::write_machine_reg;
 
-use super::binary_struct::Byte;
+// Linker symbols exported by mem.S
+extern "C" {
+    static TEXT_START: usize;
+    static TEXT_END: usize;
+    static RODATA_START: usize;
+    static RODATA_END: usize;
+    static DATA_START: usize;
+    static KERNEL_STACK_END: usize;
+    static HEAP_START: usize;
+}
+
+/// Number of pmp entries of the hart, configured by pmpcfg0 and pmpcfg2.
+const PMP_ENTRIES: usize = 16;
+
+// Address matching modes of a pmpcfg entry
+const MODE_MASK: u8 = 0b11 << 3;
+const MODE_OFF: u8 = 0 << 3;
+const MODE_TOR: u8 = 1 << 3;
+const MODE_NAPOT: u8 = 3 << 3;
+/// A locked entry also applies to M-mode and can't be changed until the next reset.
+const LOCK: u8 = 1 << 7;
+
+/// The regions locked by [init], they protect the kernel from itself.
+static KERNEL_PMP: Once<Pmp> = Once::new();
+/// The entry of [KERNEL_PMP] that keeps U-mode out of the kernel, see [guard_kernel].
+const GUARD_ENTRY: usize = 1;
+
+/// Access permissions of a pmp region.
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum Permission {
+    None = 0,
+    Read = 1 << 0,
+    Execute = 1 << 2,
+
+    // For convenience
+    ReadWrite = 1 << 0 | 1 << 1,
+    ReadExecute = 1 << 0 | 1 << 2,
+    ReadWriteExecute = 1 << 0 | 1 << 1 | 1 << 2,
+}
+
+impl Permission {
+    pub fn val(self) -> u8 {
+        self as u8
+    }
+}
+
+/// A physical memory region from `start` to `end`.
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub permission: Permission,
+}
+
+impl Region {
+    pub const fn new(start: usize, end: usize, permission: Permission) -> Self {
+        Region {
+            start,
+            end,
+            permission,
+        }
+    }
+    /// A region fits into one NAPOT entry, if its size is a power of two and it is aligned to its size.
+    fn is_napot(&self) -> bool {
+        let size = self.end - self.start;
+        size >= 8 && size.is_power_of_two() && self.start % size == 0
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    addr: usize,
+    cfg: u8,
+}
+
+/// The content of all pmp entries. Lower entries have priority over higher ones.
+#[derive(Clone, Copy)]
+pub struct Pmp {
+    entries: [Entry; PMP_ENTRIES],
+    len: usize,
+}
+
+impl Pmp {
+    pub const fn new() -> Self {
+        Pmp {
+            entries: [Entry { addr: 0, cfg: 0 }; PMP_ENTRIES],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, addr: usize, cfg: u8) {
+        assert!(self.len < PMP_ENTRIES, "No free pmp entry left");
+        self.entries[self.len] = Entry { addr, cfg };
+        self.len += 1;
+    }
+
+    /// Adds the region as NAPOT entry if possible, otherwise as TOR entry.
+    /// A `locked` region also restricts M-mode.
+    pub fn add(&mut self, region: Region, locked: bool) {
+        assert!(
+            region.start < region.end,
+            "Empty pmp region at 0x{:x}",
+            region.start
+        );
+        let mut cfg = region.permission.val();
+        if locked {
+            cfg |= LOCK;
+        }
+        if region.is_napot() {
+            let size = region.end - region.start;
+            self.push((region.start | (size / 2 - 1)) >> 2, cfg | MODE_NAPOT);
+            return;
+        }
+        // TOR takes the address of the previous entry as bottom of the range.
+        let bottom = match self.len {
+            0 => Some(0),
+            len => {
+                let prev = self.entries[len - 1];
+                match prev.cfg & MODE_MASK {
+                    MODE_OFF | MODE_TOR => Some(prev.addr << 2),
+                    _ => None,
+                }
+            }
+        };
+        if bottom != Some(region.start) {
+            self.push(region.start >> 2, MODE_OFF);
+        }
+        self.push(region.end >> 2, cfg | MODE_TOR);
+    }
+
+    /// Writes the entries to the pmp registers and turns off the unused ones.
+    /// Locked entries keep their value, the hardware ignores writes to them.
+    pub unsafe fn write(&self) {
+        let mut cfg = [0u8; PMP_ENTRIES];
+        for i in 0..self.len {
+            write_pmpaddr(i, self.entries[i].addr);
+            cfg[i] = self.entries[i].cfg;
+        }
+        let mut pmpcfg0 = [0; 8];
+        let mut pmpcfg2 = [0; 8];
+        pmpcfg0.copy_from_slice(&cfg[..8]);
+        pmpcfg2.copy_from_slice(&cfg[8..]);
+        let pmpcfg0 = usize::from_le_bytes(pmpcfg0);
+        let pmpcfg2 = usize::from_le_bytes(pmpcfg2);
+        write_machine_reg!(
+            pmpcfg0 => "pmpcfg0",
+            pmpcfg2 => "pmpcfg2"
+        );
+    }
+}
+
+/// Writes `addr` to the pmpaddr register with the index `idx`.
+unsafe fn write_pmpaddr(idx: usize, addr: usize) {
+    macro_rules! write_pmpaddr {
+        ($($i:literal),+) => {
+            match idx {
+                $($i => core::arch::asm!(concat!("csrw pmpaddr", $i, ", {}"), in(reg) addr),)+
+                _ => panic!("Invalid pmp entry: {}", idx),
+            }
+        };
+    }
+    write_pmpaddr!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+}
 
+/// Locks the kernel sections of the current hart, so not even M-mode can write its code or execute its data.
+/// The rest of the memory stays accessible for M-mode and inaccessible for U-mode.
+/// Locked entries grant their permissions to U-mode as well. So the first entry denies U-mode the whole kernel,
+/// it is unlocked and only switched on while a user prog runs, see [guard_kernel].
 pub unsafe fn init() {
-    let pmp_addr_0 = 0x80000000 >> 2; // devices  0x0-0x80000000 >> 2
-    let pmp_addr_1 = 0x80100000 >> 2; // kernel
-    let pmp_addr_2 = 0x80200000 >> 2; // user1
-    let pmp_addr_3 = 0x80300000 >> 2; // user2
-    let pmp_addr_4 = 0x80400000 >> 2; // end
-    let pmpcfg0 = 0;
-
-    write_machine_reg!(
-        pmp_addr_0 => "pmpaddr0",
-        pmp_addr_1 => "pmpaddr1",
-        pmp_addr_2 => "pmpaddr2",
-        pmp_addr_3 => "pmpaddr3",
-        pmp_addr_4 => "pmpaddr4",
-        pmpcfg0 => "pmpcfg0"
-    );
-}
-
-pub unsafe fn switch_prog_pmp(idx: usize) {
-    let prog_index = idx + 2; // device and kernel offset
-    let mut pmpcfg0 = Pmpcfg::new();
-    pmpcfg0.set_rwx(prog_index);
-    write_machine_reg!(pmpcfg0.to_usize() => "pmpcfg0");
-}
-
-#[repr(C)]
-struct Pmpcfg([Byte; 8]);
-impl Pmpcfg {
-    fn to_usize(&self) -> usize {
-        let mut arr = [0; usize::BITS as usize / 8];
-        for i in 0..self.0.len() {
-            arr[i] = self.0[i].get();
+    // The pmp registers belong to the hart, the entries are the same on every hart.
+    let pmp = KERNEL_PMP.call_once(|| {
+        let mut pmp = Pmp::new();
+        pmp.add(
+            Region::new(TEXT_START, KERNEL_STACK_END, Permission::None),
+            false,
+        );
+        assert!(
+            pmp.len == GUARD_ENTRY + 1 && pmp.entries[GUARD_ENTRY].cfg == MODE_TOR,
+            "The kernel guard is not pmp entry {}",
+            GUARD_ENTRY
+        );
+        pmp.entries[GUARD_ENTRY].cfg = MODE_OFF;
+        pmp.add(
+            Region::new(TEXT_START, TEXT_END, Permission::ReadExecute),
+            true,
+        );
+        pmp.add(
+            Region::new(RODATA_START, RODATA_END, Permission::Read),
+            true,
+        );
+        // Data, bss and the kernel stack
+        pmp.add(
+            Region::new(DATA_START, KERNEL_STACK_END, Permission::ReadWrite),
+            true,
+        );
+        pmp
+    });
+    pmp.write();
+}
+
+/// Switches on the entry that denies U-mode the kernel, before the hart returns to a user prog.
+/// An unlocked entry never restricts M-mode. But it matches before the locked kernel regions,
+/// so they don't protect the kernel from itself until [unguard_kernel].
+#[cfg(not(feature = "sbi"))]
+pub unsafe fn guard_kernel() {
+    core::arch::asm!("csrs pmpcfg0, {}", in(reg) (MODE_TOR as usize) << (8 * GUARD_ENTRY));
+}
+
+/// Switches off the entry of [guard_kernel] when the hart enters the kernel.
+#[cfg(not(feature = "sbi"))]
+pub unsafe fn unguard_kernel() {
+    core::arch::asm!("csrc pmpcfg0, {}", in(reg) (MODE_TOR as usize) << (8 * GUARD_ENTRY));
+}
+
+/// Returns the pmp entries for a user prog with its code from `start` to `text_end`
+/// and its data from `text_end` to `end`. The kernel regions come first, so they have priority.
+pub fn prog_pmp(start: usize, text_end: usize, end: usize) -> Pmp {
+    unsafe {
+        // Without kernel regions in S-mode, the firmware owns the pmp.
+        let mut pmp = KERNEL_PMP.get().copied().unwrap_or(Pmp::new());
+        pmp.add(Region::new(start, text_end, Permission::ReadExecute), false);
+        // A prog without data needs no entry for it.
+        if text_end < end {
+            pmp.add(Region::new(text_end, end, Permission::ReadWrite), false);
         }
-        return usize::from_ne_bytes(arr);
-    }
-    fn set_rwx(&mut self, at: usize) {
-        let reg = &mut self.0[at];
-        reg.at(0, true); // R
-        reg.at(1, true); // W
-        reg.at(2, true); // X
-        reg.at(3, true); // A - top of range
-    }
-    fn new() -> Self {
-        let bytes = [Byte::from(0); 8];
-        Pmpcfg(bytes)
+        // The frames of the stack, heap and mmap areas and the page tables come from the page allocator.
+        // The pmp can't tell U-mode from S-mode, as which the mmu walks the page tables, nor one frame from
+        // another. The page table of the user prog decides, which of the frames it reaches.
+        pmp.add(
+            Region::new(HEAP_START, crate::page::heap_end(), Permission::ReadWrite),
+            false,
+        );
+        pmp
     }
 }
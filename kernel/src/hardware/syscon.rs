@@ -0,0 +1,27 @@
+use super::memory_mapping::MemoryMapping;
+
+/// The test device of the qemu virt machine (SiFive test finisher).
+/// A write of one of the following values turns off or resets the machine.
+pub const BASE_ADDR: usize = 0x10_0000;
+
+/// Turns off the machine, qemu exits with 0.
+const FINISHER_PASS: u32 = 0x5555;
+/// Turns off the machine, qemu exits with the code in the upper 16 bits.
+const FINISHER_FAIL: u32 = 0x3333;
+/// Resets the machine.
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Turns off the machine, a `code` other than 0 reports a failure to qemu.
+/// Only returns, if there is no test device.
+pub unsafe fn poweroff(code: u16) {
+    let value = match code {
+        0 => FINISHER_PASS,
+        code => ((code as u32) << 16) | FINISHER_FAIL,
+    };
+    MemoryMapping::<u32>::new(BASE_ADDR).write(value);
+}
+
+/// Resets the machine. Only returns, if there is no test device.
+pub unsafe fn reboot() {
+    MemoryMapping::<u32>::new(BASE_ADDR).write(FINISHER_RESET);
+}
@@ -1,14 +1,108 @@
+// Driver of the NS16550A uart of qemu virt.
+// Received bytes are moved into a ring buffer by the interrupt handler, the user progs read them from there.
+// Printed bytes are queued in a second ring buffer and moved into the transmit fifo, whenever it runs empty
+// the THRE interrupt asks for more. In S-mode the console of the firmware prints instead.
+// See http://caro.su/msx/ocm_de1/16550.pdf
 use super::binary_struct::{BinaryStruct, Byte};
 use super::memory_mapping::MemoryMapping;
+use super::plic;
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard};
 
 const BASE_ADDR: usize = 0x1000_0000;
 
+// ier
 const RECEIVE_BIT: usize = 0;
 const TRANSMIT_BIT: usize = 1;
 const RECEIVER_LINE_STATUS_BIT: usize = 2;
 const RECEIVER_TRANSMIT_STATUS_BIT: usize = 3;
 
-/// Reserving address space for uart at [Base_ADDR].
+// isr, bit 0 is cleared while an interrupt is pending, the bits 1-3 tell which
+const NO_INTERRUPT_BIT: usize = 0;
+const ISR_ID_MASK: u8 = 0b1110;
+const ISR_LINE_STATUS: u8 = 0b0110;
+const ISR_RECEIVE: u8 = 0b0100;
+const ISR_TIMEOUT: u8 = 0b1100;
+const ISR_TRANSMIT: u8 = 0b0010;
+
+// fcr
+const FIFO_ENABLE_BIT: usize = 0;
+const CLEAR_RECEIVE_FIFO_BIT: usize = 1;
+const CLEAR_TRANSMIT_FIFO_BIT: usize = 2;
+
+// lcr
+const STOP_BITS_BIT: usize = 2;
+const PARITY_ENABLE_BIT: usize = 3;
+const EVEN_PARITY_BIT: usize = 4;
+/// Divisor latch access bit, maps the divisor onto rbr_thr_dll and ier_dlm.
+const DLAB_BIT: usize = 7;
+
+// mcr
+const DTR_BIT: usize = 0;
+const RTS_BIT: usize = 1;
+/// Connects the interrupt line on PCs.
+const OUT2_BIT: usize = 3;
+
+// lsr
+const DATA_READY_BIT: usize = 0;
+const OVERRUN_BIT: usize = 1;
+const PARITY_ERROR_BIT: usize = 2;
+const FRAMING_ERROR_BIT: usize = 3;
+const BREAK_BIT: usize = 4;
+/// The transmit fifo is empty.
+const THRE_BIT: usize = 5;
+/// The transmit fifo and the shift register are empty.
+const TEMT_BIT: usize = 6;
+
+/// Bytes the transmit fifo holds.
+const FIFO_SIZE: usize = 16;
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 1024;
+/// Input clock of the uart of qemu virt, used if the device tree has none.
+pub const DEFAULT_CLOCK: u32 = 3_686_400;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+/// Line settings of the uart.
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub baud: u32,
+    /// 5 to 8 bits per character.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2 stop bits.
+    pub stop_bits: u8,
+}
+
+impl Config {
+    /// 115200 baud 8N1, what qemu and the usual terminals expect.
+    pub const DEFAULT: Config = Config {
+        baud: 115_200,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+    };
+}
+
+/// Errors reported by the lsr since boot.
+#[derive(Clone, Copy)]
+pub struct LineErrors {
+    /// A byte arrived while the receive fifo was full.
+    pub overrun: usize,
+    pub parity: usize,
+    /// A byte without a valid stop bit arrived.
+    pub framing: usize,
+    pub breaks: usize,
+    /// Received bytes dropped, because the receive buffer was full.
+    pub dropped: usize,
+}
+
+/// Reserving address space for uart at [BASE_ADDR], until [set_base] is called.
 /// This includes address space for the register:
 /// - rbr_thr_dll = Receive Buffer Register
 /// - ier_dlm = Interrupt Enable Register
@@ -18,68 +112,242 @@ const RECEIVER_TRANSMIT_STATUS_BIT: usize = 3;
 /// - lsr = Line Status Register
 /// - msr = Memory Status Register
 /// - scr = Scratched Register Read/Write
-static mut UART: UART = UART {
+///
+/// The interrupt handler uses it as well, so the interrupts are off while it is locked.
+static UART: IrqSpinLock<UART> = IrqSpinLock::new(UART {
     reg: UartRegister::new(BASE_ADDR),
-};
+    rx: RingBuffer::new(),
+    tx: RingBuffer::new(),
+    errors: LineErrors {
+        overrun: 0,
+        parity: 0,
+        framing: 0,
+        breaks: 0,
+        dropped: 0,
+    },
+    interrupts: false,
+    tx_interrupt: false,
+});
+
+/// Moves the uart to `addr`, e.g. the one found in the device tree.
+pub unsafe fn set_base(addr: usize) {
+    UART.lock().reg = UartRegister::new(addr);
+}
 
-/// Initializes the interrupts for uart in the ier_dlm register.
+/// Sets the default line settings, turns on the fifos and the interrupts and registers their handler at the plic.
 pub unsafe fn init() {
-    let mem_ier = &mut UART.reg.ier_dlm;
-    let mut ier = BinaryStruct::from(0);
-    ier.at(RECEIVE_BIT, true); // receive interrupt
-    ier.at(TRANSMIT_BIT, false); // transmit interrupt
-    ier.at(RECEIVER_LINE_STATUS_BIT, false); // receiver line status interrupt
-    ier.at(RECEIVER_TRANSMIT_STATUS_BIT, false); // receiver transmit status interrupt
-    mem_ier.write(ier);
+    configure(&Config::DEFAULT);
+    let mut uart = UART.lock();
+    uart.interrupts = true;
+    uart.write_ier();
+    // Sends what was printed while the kernel was set up.
+    uart.start_transmit();
+    drop(uart);
+    plic::register(plic::UART_SOURCE, 5, handle_interrupt);
 }
 
-/// Only call if an interrupt happened. Returns the char.
-pub unsafe fn read_char() -> char {
-    return UART.get_char();
+/// Changes the line settings, after the queued bytes are sent.
+/// The divisor is computed from the clock of the uart in the device tree.
+pub unsafe fn configure(config: &Config) {
+    let clock = crate::platform::get().uart_clock;
+    UART.lock().configure(clock, config);
 }
 
-/// print a str over uart on the terminal
-pub unsafe fn print_str(str: &str) {
-    for c in str.chars() {
-        UART.print_char(c);
-    }
+unsafe fn handle_interrupt(_source: usize) {
+    UART.lock().handle_interrupt();
+}
+
+/// Returns the next received byte, if there is one.
+pub unsafe fn read_byte() -> Option<u8> {
+    let mut uart = UART.lock();
+    // The kernel runs with the interrupts off, so the fifo may hold bytes the handler has not seen yet.
+    uart.receive();
+    uart.rx.pop()
+}
+
+/// Prints the name and version of the OS, once the kernel is set up.
+pub fn print_os() {
+    crate::println!("Rust OS v1.0");
 }
+
 /// print a char over uart on the terminal
 pub unsafe fn print_char(char: char) {
-    UART.print_char(char);
+    UART.lock().print_char(char);
 }
-/// get a char from the user over uart
-pub unsafe fn get_uart() -> &'static mut UART {
-    &mut UART
+
+/// Sends the queued bytes before the machine is turned off.
+/// The lock is not taken, the panicking code may hold it.
+pub unsafe fn flush() {
+    UART.force_get().flush();
+}
+
+/// Returns the errors counted since boot.
+#[allow(dead_code)]
+pub fn line_errors() -> LineErrors {
+    UART.lock().errors
+}
+
+/// Writes a byte directly to the uart, for the built-in SBI layer in M-mode.
+/// The lock is not taken, the kernel holds it while its console calls the SBI layer.
+#[cfg(feature = "builtin-sbi")]
+pub unsafe fn write_byte(byte: u8) {
+    UART.force_get().write_byte(byte);
+}
+/// Returns the received byte, if there is one. For the built-in SBI layer in M-mode.
+#[cfg(feature = "builtin-sbi")]
+pub unsafe fn try_read_byte() -> Option<u8> {
+    UART.force_get().try_read_byte()
+}
+/// Locks the uart for printing, it is unlocked when the guard is dropped.
+pub fn get_uart() -> IrqSpinLockGuard<'static, UART> {
+    UART.lock()
+}
+/// Returns the uart without locking it, for the panic handler. The panicking code may hold the lock.
+pub unsafe fn force_get_uart() -> &'static mut UART {
+    UART.force_get()
 }
 
 /// implementation for print_char, get_char
 pub struct UART {
     reg: UartRegister,
+    /// Received bytes, not read yet.
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    /// Printed bytes, waiting for room in the transmit fifo.
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    errors: LineErrors,
+    /// Set by [init], before the ier is left alone.
+    interrupts: bool,
+    /// The THRE interrupt is on.
+    tx_interrupt: bool,
 }
 
 impl UART {
-    /// Print a char if the lsr is free (the bit 5 is set)
+    /// Print a char over the uart.
+    /// In S-mode the char is printed by the console of the firmware.
     fn print_char(&mut self, char: char) {
+        self.transmit(char as u8);
+    }
+
+    /// Queues a byte for the transmit fifo. Waits for room, if the buffer is full.
+    fn transmit(&mut self, byte: u8) {
+        #[cfg(feature = "sbi")]
+        crate::sbi::console_putchar(byte);
+        #[cfg(not(feature = "sbi"))]
         unsafe {
-            // Loop until char is send to the buffer register
-            loop {
-                let lsr = self.reg.lsr.read();
-                //Check if we can overwrite the buffer register
-                if lsr.is_set(5) {
-                    self.reg.rbr_thr_dll.write(char as u8);
-                    return;
+            // The interrupts may be off, so the fifo is filled here until there is room.
+            while self.tx.is_full() {
+                self.start_transmit();
+            }
+            self.tx.push(byte);
+            self.start_transmit();
+        }
+    }
+
+    /// Fills the transmit fifo from the buffer once it ran empty.
+    /// The THRE interrupt stays on while bytes are left, so they are sent after the kernel returns to a user prog.
+    unsafe fn start_transmit(&mut self) {
+        if self.reg.lsr.read().is_set(THRE_BIT) {
+            for _ in 0..FIFO_SIZE {
+                match self.tx.pop() {
+                    Some(byte) => self.reg.rbr_thr_dll.write(byte),
+                    None => break,
                 }
             }
         }
+        if self.interrupts && self.tx_interrupt == self.tx.is_empty() {
+            self.write_ier();
+        }
+    }
+
+    /// Sends the queued bytes and waits until they left the uart.
+    pub unsafe fn flush(&mut self) {
+        while !self.tx.is_empty() || !self.reg.lsr.read().is_set(TEMT_BIT) {
+            self.start_transmit();
+        }
+    }
+
+    /// Moves the received bytes into the buffer and echoes them.
+    unsafe fn receive(&mut self) {
+        loop {
+            // Reading the lsr clears its error bits.
+            let lsr = self.reg.lsr.read();
+            self.count_errors(lsr);
+            if !lsr.is_set(DATA_READY_BIT) {
+                return;
+            }
+            let byte = self.reg.rbr_thr_dll.read();
+            if !self.rx.push(byte) {
+                self.errors.dropped += 1;
+            }
+            self.transmit(byte);
+        }
     }
 
-    unsafe fn get_char(&mut self) -> char {
-        let lsr = &self.reg.lsr;
-        while !lsr.read().is_set(0) {}
-        let output = self.reg.rbr_thr_dll.read() as char;
-        //print_char(output);
-        return output;
+    fn count_errors(&mut self, lsr: Byte) {
+        let errors = &mut self.errors;
+        errors.overrun += lsr.is_set(OVERRUN_BIT) as usize;
+        errors.parity += lsr.is_set(PARITY_ERROR_BIT) as usize;
+        errors.framing += lsr.is_set(FRAMING_ERROR_BIT) as usize;
+        errors.breaks += lsr.is_set(BREAK_BIT) as usize;
+    }
+
+    /// Handles the pending interrupts of the uart, the isr tells one at a time.
+    unsafe fn handle_interrupt(&mut self) {
+        loop {
+            let isr = self.reg.isr_fcr.read();
+            if isr.is_set(NO_INTERRUPT_BIT) {
+                return;
+            }
+            match isr.get() & ISR_ID_MASK {
+                ISR_LINE_STATUS | ISR_RECEIVE | ISR_TIMEOUT => self.receive(),
+                ISR_TRANSMIT => self.start_transmit(),
+                // Modem status, cleared by reading the msr
+                _ => {
+                    self.reg.msr.read();
+                }
+            }
+        }
+    }
+
+    /// Turns on the receive and line status interrupts, and the THRE interrupt while bytes are queued.
+    unsafe fn write_ier(&mut self) {
+        self.tx_interrupt = !self.tx.is_empty();
+        let mut ier = BinaryStruct::from(0);
+        ier.at(RECEIVE_BIT, true); // receive interrupt
+        ier.at(TRANSMIT_BIT, self.tx_interrupt); // transmit interrupt
+        ier.at(RECEIVER_LINE_STATUS_BIT, true); // receiver line status interrupt
+        ier.at(RECEIVER_TRANSMIT_STATUS_BIT, false); // receiver transmit status interrupt
+        self.reg.ier_dlm.write(ier);
+    }
+
+    /// Programs the line settings, after the queued bytes are sent with the old ones.
+    unsafe fn configure(&mut self, clock: u32, config: &Config) {
+        self.flush();
+        self.reg.configure(clock, config);
+    }
+
+    /// Write a byte if the lsr is free (the bit 5 is set)
+    #[allow(dead_code)]
+    unsafe fn write_byte(&mut self, byte: u8) {
+        // Loop until byte is send to the buffer register
+        loop {
+            let lsr = self.reg.lsr.read();
+            //Check if we can overwrite the buffer register
+            if lsr.is_set(THRE_BIT) {
+                self.reg.rbr_thr_dll.write(byte);
+                return;
+            }
+        }
+    }
+
+    /// Read a byte without waiting, if the data ready bit of the lsr is set.
+    #[allow(dead_code)]
+    unsafe fn try_read_byte(&mut self) -> Option<u8> {
+        if self.reg.lsr.read().is_set(DATA_READY_BIT) {
+            Some(self.reg.rbr_thr_dll.read())
+        } else {
+            None
+        }
     }
 }
 
@@ -93,6 +361,88 @@ impl core::fmt::Write for UART {
     }
 }
 
+/// A uart the kernel polls, without buffers and interrupts. The gdb stub talks over it,
+/// while the kernel waits in a trap with the interrupts off.
+pub struct PolledUart {
+    reg: UartRegister,
+}
+
+impl PolledUart {
+    /// A second uart at `addr`, set to the default line settings. Its interrupts stay off.
+    pub unsafe fn init(addr: usize, clock: u32) -> Self {
+        let mut reg = UartRegister::new(addr);
+        reg.configure(clock, &Config::DEFAULT);
+        reg.ier_dlm.write(BinaryStruct::from(0));
+        PolledUart { reg }
+    }
+
+    /// The console, after the bytes its driver queued are sent.
+    /// Its driver does not see what is read here, the interrupts are off while the kernel polls.
+    pub unsafe fn console() -> Self {
+        UART.lock().flush();
+        PolledUart {
+            reg: UartRegister::new(crate::platform::get().uart),
+        }
+    }
+
+    /// Waits until the byte fits into the transmit fifo.
+    pub unsafe fn write(&mut self, byte: u8) {
+        while !self.reg.lsr.read().is_set(THRE_BIT) {}
+        self.reg.rbr_thr_dll.write(byte);
+    }
+
+    /// Waits for the next received byte.
+    pub unsafe fn read(&mut self) -> u8 {
+        while !self.reg.lsr.read().is_set(DATA_READY_BIT) {}
+        self.reg.rbr_thr_dll.read()
+    }
+}
+
+/// A queue of bytes, the oldest is taken first.
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        RingBuffer {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends `byte`. Returns false, if the buffer is full.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
 #[allow(dead_code)]
 struct UartRegister {
     /// Receive Buffer Register, Transmit Holding Register | LSB of Divisor Latch when enabled.
@@ -133,4 +483,47 @@ impl UartRegister {
             scr,
         }
     }
+
+    /// Programs the divisor latch and the lcr with `config` and resets the fifos.
+    unsafe fn configure(&mut self, clock: u32, config: &Config) {
+        assert!(
+            (5..=8).contains(&config.data_bits),
+            "Invalid uart data bits: {}",
+            config.data_bits
+        );
+        assert!(
+            config.stop_bits == 1 || config.stop_bits == 2,
+            "Invalid uart stop bits: {}",
+            config.stop_bits
+        );
+        let divisor = clock / (16 * config.baud.max(1));
+        assert!(
+            divisor > 0 && divisor <= u16::MAX as u32,
+            "Invalid uart baud rate: {}",
+            config.baud
+        );
+        let mut lcr = BinaryStruct::from(config.data_bits - 5);
+        lcr.at(STOP_BITS_BIT, config.stop_bits == 2);
+        lcr.at(PARITY_ENABLE_BIT, config.parity != Parity::None);
+        lcr.at(EVEN_PARITY_BIT, config.parity == Parity::Even);
+        let mut latch = lcr;
+        latch.at(DLAB_BIT, true);
+        self.lcr.write(latch);
+        self.rbr_thr_dll.write(divisor as u8);
+        self.ier_dlm.write(BinaryStruct::from((divisor >> 8) as u8));
+        self.lcr.write(lcr);
+
+        // The trigger level in bits 6-7 stays 0, every received byte raises an interrupt.
+        let mut fcr = BinaryStruct::from(0);
+        fcr.at(FIFO_ENABLE_BIT, true);
+        fcr.at(CLEAR_RECEIVE_FIFO_BIT, true);
+        fcr.at(CLEAR_TRANSMIT_FIFO_BIT, true);
+        self.isr_fcr.write(fcr);
+
+        let mut mcr = BinaryStruct::from(0);
+        mcr.at(DTR_BIT, true);
+        mcr.at(RTS_BIT, true);
+        mcr.at(OUT2_BIT, true);
+        self.mcr.write(mcr);
+    }
 }
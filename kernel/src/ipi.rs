@@ -0,0 +1,107 @@
+// Inter-processor interrupts. A hart posts a request in the mask of the target hart and raises its software interrupt,
+// with the msip register of the clint in M-mode or the IPI extension of the firmware in S-mode.
+// The target handles all requests posted until then in one interrupt.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cpu::{self, MAX_HARTS};
+use crate::hardware::clint;
+use crate::smp;
+
+#[repr(usize)]
+#[derive(Clone, Copy)]
+pub enum Ipi {
+    /// Pick up a user prog that became ready, e.g. on an idle hart.
+    Reschedule = 1 << 0,
+    /// Flush the cached translations of the address spaces posted in [SHOOTDOWNS].
+    TlbShootdown = 1 << 1,
+    /// Stop the hart, another one panicked.
+    Halt = 1 << 2,
+    /// Fetch the instructions again, the code of a user prog changed.
+    FenceI = 1 << 3,
+}
+
+const NONE: AtomicUsize = AtomicUsize::new(0);
+/// The requests posted to each hart.
+static REQUESTS: [AtomicUsize; MAX_HARTS] = [NONE; MAX_HARTS];
+/// The asids each hart has to flush, one bit per asid.
+static SHOOTDOWNS: [AtomicUsize; MAX_HARTS] = [NONE; MAX_HARTS];
+
+/// Posts `ipi` to `hart` and interrupts it.
+pub fn send(hart: usize, ipi: Ipi) {
+    REQUESTS[hart].fetch_or(ipi as usize, Ordering::Release);
+    unsafe { clint::send_soft(hart) };
+}
+
+/// Posts `ipi` to every other hart running the kernel.
+pub fn send_others(ipi: Ipi) {
+    for hart in smp::others() {
+        send(hart, ipi);
+    }
+}
+
+/// Flushes the translation of `vaddr` in the address space `asid` on this hart, and all of `asid` on the others.
+pub fn flush_page(vaddr: usize, asid: usize) {
+    cpu::satp_fence(vaddr, asid);
+    shootdown(asid);
+}
+
+/// Makes every hart fetch the instructions written before.
+pub fn fence_i() {
+    cpu::instruction_fence();
+    send_others(Ipi::FenceI);
+}
+
+/// Flushes the translations of the address space `asid` on every hart.
+pub fn flush_asid(asid: usize) {
+    cpu::satp_fence_asid(asid);
+    shootdown(asid);
+}
+
+/// Asks the other harts to flush `asid`. They may cache its translations from a user prog that ran there before.
+fn shootdown(asid: usize) {
+    assert!(asid < usize::BITS as usize, "Invalid asid: {}", asid);
+    for hart in smp::others() {
+        SHOOTDOWNS[hart].fetch_or(1 << asid, Ordering::Release);
+        send(hart, Ipi::TlbShootdown);
+    }
+}
+
+/// Flushes the address spaces the other harts changed.
+/// Called before the hart runs a user prog, the interrupt may not have arrived yet.
+pub fn flush_pending() {
+    let asids = SHOOTDOWNS[cpu::hart_id()].swap(0, Ordering::Acquire);
+    for asid in (0..usize::BITS as usize).filter(|asid| asids & 1 << asid != 0) {
+        cpu::satp_fence_asid(asid);
+    }
+}
+
+/// Stops the hart, if another one asked for it.
+/// Checked before the kernel lock is taken, the panicking hart may hold it.
+pub fn halt_if_requested() {
+    if REQUESTS[cpu::hart_id()].load(Ordering::Acquire) & Ipi::Halt as usize != 0 {
+        halt();
+    }
+}
+
+/// Handles the software interrupt of the hart. Returns true if it should pick another user prog.
+pub fn handle() -> bool {
+    unsafe { clint::clear_soft() };
+    let requests = REQUESTS[cpu::hart_id()].swap(0, Ordering::Acquire);
+    if requests & Ipi::Halt as usize != 0 {
+        halt();
+    }
+    if requests & Ipi::TlbShootdown as usize != 0 {
+        flush_pending();
+    }
+    if requests & Ipi::FenceI as usize != 0 {
+        cpu::instruction_fence();
+    }
+    requests & Ipi::Reschedule as usize != 0
+}
+
+fn halt() -> ! {
+    cpu::disable_interrupts();
+    loop {
+        cpu::wait_for_interrupt();
+    }
+}
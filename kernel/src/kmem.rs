@@ -1,3 +1,11 @@
+use crate::{
+    cpu::{self, SatpMode},
+    hardware::{plic, syscon},
+    mmu::{self, EntryBits, Table},
+    page, platform,
+};
+
+// Linker symbols exported by mem.S
 extern "C" {
     static TEXT_START: usize;
     static TEXT_END: usize;
@@ -10,175 +18,178 @@ extern "C" {
     static KERNEL_STACK_START: usize;
     static KERNEL_STACK_END: usize;
     static HEAP_START: usize;
-    static HEAP_END: usize;
+    static USER_START: usize;
+    static USER_END: usize;
 }
 
-pub fn id_map_range(root: &mut page::Table, start: usize, end: usize, bits: i64) {
-    let mut memaddr = start & !(page::PAGE_SIZE - 1);
-    let num_kb_pages = (page::aling_value(end, 12) - memaddr) / page::PAGE_SIZE;
+/// Size of the stack the trap handler runs on.
+const TRAP_STACK_PAGES: usize = 4;
+
+/// The root table of the kernel address space.
+static mut KERNEL_TABLE: *mut Table = core::ptr::null_mut();
 
+/// Identity maps every page from `start` to `end` with the given `bits`.
+pub fn id_map_range(root: &mut Table, start: usize, end: usize, bits: usize) {
+    let mut memaddr = start & !(page::PAGE_SIZE - 1);
+    let num_kb_pages = (page::align_val(end, 12) - memaddr) / page::PAGE_SIZE;
     for _ in 0..num_kb_pages {
-        page::map(root, memaddr, memaddr, bits, 0);
-        memaddr += 1 << 12;
+        mmu::map(root, memaddr, memaddr, bits, 0);
+        memaddr += page::PAGE_SIZE;
     }
 }
 
-#[no_mangle]
-extern "C" fn kinit() {
-    uart::Uart::new(0x1000_000).init();
-    page::init();
-    kmem::init();
-
-    let root_ptr = kmem::get_page_table();
-    let root_u = root_ptr as usize;
-    let mut root = unsafe { root_ptr.as_mut().unwrap() };
-    let kheap_head = kmem::get_head() as usize;
-    let total_pages = kmem::get_num_allocations();
-    println!();
-    println!();
+/// Returns the root table of the kernel address space.
+pub fn table() -> &'static mut Table {
     unsafe {
-        println!("TEXT: 0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
-        println!("RODATA : 0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
-        println!("TEXT: 0x{:x} -> 0x{:x}", DATA_START, DATA_END);
-        println!("TEXT: 0x{:x} -> 0x{:x}", BSS_START, BSS_END);
-
-        println!(
-            "STACK: 0x{:x} -> 0x{:x}",
-            KERNEL_STACK_START, KERNEL_STACK_END
-        );
-
-        println!(
-            "HEAP: 0x{:x} -> 0x{:x}",
-            kheap_head,
-            kheap_head + total_pages * page::PAGE_SIZE
-        );
+        KERNEL_TABLE
+            .as_mut()
+            .expect("Tried to access the kernel page table before kmem::init")
     }
-    id_map_range(
-        &mut root,
-        kheap_head,
-        kheap_head + total_pages * page::PAGE_SIZE,
-        page::EntryBits::ReadWrite.val(),
-    );
-
-    unsafe {
-        let num_pages = HEAP_SIZE / page::PAGE_SIZE;
-        // Map heap descriptors
-        id_map_range(
-            &mut root,
-            HEAP_START,
-            HEAP_START + num_pages,
-            page::EntryBits::ReadWrite.val(),
-        );
-
-        // Map executable section
-        id_map_range(
-            &mut root,
-            TEXT_START,
-            TEXT_END,
-            page::EntryBits::ReadExecute.val(),
-        );
-
-        // Map rodata section
-
-        id_map_range(
-            &mut root,
-            RODATA_START,
-            RODATA_END,
-            page::EntryBits::ReadExecute.val(),
-        );
-
-        // Map data section
-        id_map_range(
-            &mut root,
-            DATA_START,
-            DATA_END,
-            page::EntryBits::ReadWrite.val(),
-        );
-
-        // Map bss section
-        id_map_range(
-            &mut root,
-            BSS_START,
-            BSS_END,
-            page::EntryBits::ReadWrite.val(),
-        );
-
-        // Map kernel stack
-        id_map_range(
-            &mut root,
-            KERNEL_STACK_START,
-            KERNEL_STACK_END,
-            page::EntryBits::ReadWrite.val(),
-        );
+}
 
-        // UART
-        id_map_range(
-            &mut root,
-            0x1000_0000,
-            0x1000_0100,
-            page::EntryBits::ReadWrite.val(),
-        );
+/// Copies the entries of the kernel root table into the root table of a user prog.
+/// In S-mode the kernel runs on the page table of the interrupted user prog,
+/// the entries are not accessible for the user prog, since they lack the user bit.
+#[cfg(feature = "sbi")]
+pub fn share(root: &mut Table) {
+    let kernel = table();
+    for (entry, kernel_entry) in root.entries.iter_mut().zip(kernel.entries.iter()) {
+        if kernel_entry.is_valid() {
+            assert!(entry.is_invalid(), "User mapping overlaps the kernel");
+            *entry = *kernel_entry;
+        }
+    }
+}
 
-        // CLINT -> MSIP
-        id_map_range(
-            &mut root,
-            0x0200_0000,
-            0x0200_ffff,
-            page::EntryBits::ReadWrite.val(),
-        );
+/// Returns true if the user range from `start` to `end` lies in a root entry that [share] copies from the kernel.
+/// The user prog can't use such a range, its pages would end up in the tables of the kernel.
+/// With more than 2 GiB of memory the kernel mapping reaches the user heap.
+pub fn overlaps_kernel(start: usize, end: usize) -> bool {
+    if !cfg!(feature = "sbi") || start >= end {
+        return false;
+    }
+    let kernel = table();
+    (start >> 30..=(end - 1) >> 30)
+        .any(|i| kernel.entries.get(i).is_some_and(|entry| entry.is_valid()))
+}
 
-        // PLIC
-        id_map_range(
-            &mut root,
-            0x0c00_0000,
-            0x0c00_2000,
-            page::EntryBits::ReadWrite.val(),
-        );
+/// Removes the entries copied by [share], so they are not freed with the user page table.
+#[cfg(feature = "sbi")]
+pub fn unshare(root: &mut Table) {
+    let kernel = table();
+    for (entry, kernel_entry) in root.entries.iter_mut().zip(kernel.entries.iter()) {
+        if kernel_entry.is_valid() {
+            entry.set_entry(0);
+        }
+    }
+}
 
+/// Builds the kernel address space from the linker symbols and the devices of the platform.
+/// The page allocator has to be initialized before.
+pub unsafe fn init() {
+    let root_ptr = page::zalloc(1) as *mut Table;
+    KERNEL_TABLE = root_ptr;
+    let root = table();
+
+    // Kernel sections
+    id_map_range(root, TEXT_START, TEXT_END, EntryBits::ReadExecute.val());
+    id_map_range(root, RODATA_START, RODATA_END, EntryBits::Read.val());
+    id_map_range(root, DATA_START, DATA_END, EntryBits::ReadWrite.val());
+    id_map_range(root, BSS_START, BSS_END, EntryBits::ReadWrite.val());
+    id_map_range(
+        root,
+        KERNEL_STACK_START,
+        KERNEL_STACK_END,
+        EntryBits::ReadWrite.val(),
+    );
+    // The loaded user progs, the kernel reads their headers and copies their pages
+    id_map_range(root, USER_START, USER_END, EntryBits::ReadWrite.val());
+    // Page descriptors and allocated pages
+    id_map_range(
+        root,
+        HEAP_START,
+        page::heap_end(),
+        EntryBits::ReadWrite.val(),
+    );
+    // Devices
+    id_map_range(
+        root,
+        syscon::BASE_ADDR,
+        syscon::BASE_ADDR + 0x1000,
+        EntryBits::ReadWrite.val(),
+    );
+    let platform = platform::get();
+    id_map_range(
+        root,
+        platform.uart,
+        platform.uart + 0x100,
+        EntryBits::ReadWrite.val(),
+    );
+    if let Some(debug_uart) = platform.debug_uart {
         id_map_range(
-            &mut root,
-            0x0c20_0000,
-            0x0c20_8000,
-            page::EntryBits::ReadWrite.val(),
+            root,
+            debug_uart,
+            debug_uart + 0x100,
+            EntryBits::ReadWrite.val(),
         );
-        page::print_page_allocations();
-
-        let satp_value = cpu::build_satp(8, 0, root_u);
-
-        unsafe {
-            cpu::mscratch_write(
-                (&mut cpu::KERNEL_TRAP_FRAME[0] as *mut cpu::KernelTrapFrame) as usize,
-            );
-
-            cpu::sscratch_write(cpu::mscratch_read());
-            cpu::KERNEL_TRAP_FRAME[0].satp = satp_value;
-
-            cpu::KERNEL_TRAP_FRAME[0].trap_stack = page::zalloc(1).add(4096);
-
-            id_map_range(
-                &mut root,
-                cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize - 4096,
-                cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize,
-                page::EntryBits::ReadWrite.val(),
-            );
-
-            id_map_range(
-                &mut root,
-                cpu::mscratch_read(),
-                cpu::mscratch_read() | 0xfff,
-                page::EntryBits::ReadWrite.val(),
-            );
-
-            let p = cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize;
-            let m = page::virt_to_phys(&root, p).unwrap_or(0);
-            println!("Walk 0x{:x} = 0x{:x}", p, m);
-        }
-
-        println!("Setting 0x{:x}", satp_value);
-        println!("Scratch reg = 0x{:x}", cpu::mscratch_read());
-        cpu::satp_write(satp_value);
-        cpu::satp_fence();
     }
+    id_map_range(
+        root,
+        platform.clint,
+        platform.clint + 0x1_0000,
+        EntryBits::ReadWrite.val(),
+    );
+    id_map_range(
+        root,
+        platform.plic,
+        platform.plic + plic::ENABLE_OFFSET + 0x1000,
+        EntryBits::ReadWrite.val(),
+    );
+    id_map_range(
+        root,
+        platform.plic + plic::CONTEXT_OFFSET,
+        // Two contexts per hart
+        platform.plic + plic::CONTEXT_OFFSET + 0x1000 * 2 * cpu::MAX_HARTS,
+        EntryBits::ReadWrite.val(),
+    );
+
+    crate::info!("TEXT:   0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
+    crate::info!("RODATA: 0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
+    crate::info!("DATA:   0x{:x} -> 0x{:x}", DATA_START, DATA_END);
+    crate::info!("BSS:    0x{:x} -> 0x{:x}", BSS_START, BSS_END);
+    crate::info!(
+        "STACK:  0x{:x} -> 0x{:x}",
+        KERNEL_STACK_START,
+        KERNEL_STACK_END
+    );
+    crate::info!(
+        "HEAP:   0x{:x} -> 0x{:x}, {} free pages",
+        HEAP_START,
+        page::heap_end(),
+        page::free_pages()
+    );
 }
 
-pub mod mem;
+/// Allocates the trap stack of the current hart, points its trap scratch register to its trap frame
+/// and turns on paging. Called by every hart after [init].
+pub unsafe fn init_hart() {
+    let root = table();
+    // Trap stack, the heap is mapped already
+    let trap_stack = page::zalloc(TRAP_STACK_PAGES);
+    assert!(!trap_stack.is_null(), "Out of memory for the trap stack");
+    let satp = cpu::build_satp(SatpMode::Sv39, 0, root as *mut Table as usize);
+    let frame = cpu::trap_frame();
+    frame.trap_stack = trap_stack as usize + TRAP_STACK_PAGES * page::PAGE_SIZE;
+    frame.satp = satp;
+    frame.hart_id = cpu::hart_id();
+    cpu::scratch_write(frame as *mut cpu::TrapFrame as usize);
+
+    // Sanity check of the page table walk
+    let walk = mmu::virt_to_phys(root, frame.trap_stack - 1);
+    assert_eq!(walk, Some(frame.trap_stack - 1), "Kernel mapping is broken");
+
+    // In M-mode paging only applies to the user progs, the kernel runs on physical addresses.
+    // In S-mode the kernel runs on the identity mapping. Every user prog has its own address space, see vma.rs.
+    cpu::satp_write(satp);
+    cpu::satp_fence_asid(0);
+}
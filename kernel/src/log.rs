@@ -0,0 +1,305 @@
+// Kernel log. The messages of the log macros in macros.rs are printed on the uart with a timestamp and
+// the module they come from, and kept in a ring buffer the user progs read with the ReadLog syscall, like dmesg.
+// Messages above [STATIC_MAX_LEVEL] are removed at compile time, the ones above [max_level] are dropped at runtime.
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::hardware::{clint, uart};
+use crate::platform;
+use crate::sync::IrqSpinLock;
+
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// Parses the name in any case or the number of a level.
+    fn parse(value: &str) -> Option<Level> {
+        Level::ALL.into_iter().find(|level| {
+            level.name().eq_ignore_ascii_case(value) || value.parse() == Ok(*level as usize)
+        })
+    }
+}
+
+/// Highest level compiled in. Debug messages need a debug build, trace messages the `log-trace` feature.
+pub const STATIC_MAX_LEVEL: Level = if cfg!(feature = "log-trace") {
+    Level::Trace
+} else if cfg!(debug_assertions) {
+    Level::Debug
+} else {
+    Level::Info
+};
+
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Bytes of the ring buffer, the oldest messages are overwritten.
+const DMESG_SIZE: usize = 16 * 1024;
+/// Longest message with its timestamp, level and module.
+const MAX_LINE: usize = 512;
+
+/// Trap handlers log as well, so the interrupts are off while it is locked.
+static DMESG: IrqSpinLock<Dmesg> = IrqSpinLock::new(Dmesg {
+    buf: [0; DMESG_SIZE],
+    head: 0,
+    len: 0,
+});
+
+/// Sets the runtime level from `loglevel=<name or number>` in the boot arguments, e.g. `-append loglevel=debug`.
+pub fn init() {
+    let level = platform::get()
+        .bootargs
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix("loglevel="));
+    if let Some(level) = level {
+        match Level::parse(level) {
+            Some(level) => set_max_level(level),
+            None => crate::warn!("Unknown log level: {}", level),
+        }
+    }
+}
+
+/// Returns the highest level that is logged.
+pub fn max_level() -> usize {
+    MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Logs the messages up to `level`, the ones above [STATIC_MAX_LEVEL] stay removed.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Prints a message of the log macros and keeps it in the ring buffer. `module` is the path of its module.
+pub fn write(level: Level, module: &str, args: fmt::Arguments) {
+    if level as usize > max_level() {
+        return;
+    }
+    // Without the crate name
+    let tag = module.split_once("::").map_or(module, |(_, tag)| tag);
+    let (secs, micros) = timestamp();
+    let line = Line {
+        secs,
+        micros,
+        level,
+        tag,
+        args,
+    };
+    // The arguments are formatted once, before any lock is taken
+    let mut buf = LineBuf {
+        buf: [0; MAX_LINE],
+        len: 0,
+        cut: false,
+    };
+    write!(buf, "{}", line).ok();
+    let text = buf.finish();
+    // Each one takes the line at once, so the lines of the harts are not mixed
+    DMESG.lock().write_str(text).ok();
+    uart::get_uart().write_str(text).ok();
+}
+
+/// Copies the buffered log, starting `offset` bytes after the oldest one, into `buf`. Returns the number of bytes.
+pub fn read(offset: usize, buf: &mut [u8]) -> usize {
+    DMESG.lock().read(offset, buf)
+}
+
+/// Returns the seconds and microseconds since boot from the `time` counter. 0 before the platform is known.
+fn timestamp() -> (u64, u64) {
+    let platform = match platform::try_get() {
+        Some(platform) => platform,
+        None => return (0, 0),
+    };
+    let time = unsafe { clint::time() };
+    let timebase = platform.timebase;
+    (time / timebase, time % timebase * 1_000_000 / timebase)
+}
+
+struct Line<'a> {
+    secs: u64,
+    micros: u64,
+    level: Level,
+    tag: &'a str,
+    args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "[{:5}.{:06}] {:5} {}: {}",
+            self.secs,
+            self.micros,
+            self.level.name(),
+            self.tag,
+            self.args
+        )
+    }
+}
+
+/// A line formatted on the stack, longer lines are cut off at [MAX_LINE] bytes.
+struct LineBuf {
+    buf: [u8; MAX_LINE],
+    len: usize,
+    cut: bool,
+}
+
+impl LineBuf {
+    /// Returns the line, a cut off one ends with a newline as well.
+    fn finish(&mut self) -> &str {
+        if self.cut {
+            self.buf[self.len] = b'\n';
+            self.len += 1;
+        }
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.cut {
+            return Ok(());
+        }
+        // One byte stays free for the newline of a cut off line.
+        let mut count = s.len().min(MAX_LINE - 1 - self.len);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        self.cut = count < s.len();
+        Ok(())
+    }
+}
+
+/// The recent messages, `len` bytes from `head` on.
+struct Dmesg {
+    buf: [u8; DMESG_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Dmesg {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let count = self.len.saturating_sub(offset).min(buf.len());
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.buf[(self.head + offset + i) % DMESG_SIZE];
+        }
+        count
+    }
+}
+
+impl Write for Dmesg {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[(self.head + self.len) % DMESG_SIZE] = byte;
+            if self.len == DMESG_SIZE {
+                self.head = (self.head + 1) % DMESG_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dmesg() -> Dmesg {
+        Dmesg {
+            buf: [0; DMESG_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn line() -> LineBuf {
+        LineBuf {
+            buf: [0; MAX_LINE],
+            len: 0,
+            cut: false,
+        }
+    }
+
+    #[test]
+    fn read_from_offset() {
+        let mut dmesg = dmesg();
+        dmesg.write_str("first\nsecond\n").unwrap();
+        let mut buf = [0; 32];
+        let count = dmesg.read(0, &mut buf);
+        assert_eq!(&buf[..count], b"first\nsecond\n");
+        let count = dmesg.read(6, &mut buf);
+        assert_eq!(&buf[..count], b"second\n");
+        // A short buffer gets the start, an offset behind the end nothing.
+        assert_eq!(dmesg.read(0, &mut buf[..3]), 3);
+        assert_eq!(&buf[..3], b"fir");
+        assert_eq!(dmesg.read(13, &mut buf), 0);
+        assert_eq!(dmesg.read(usize::MAX, &mut buf), 0);
+    }
+
+    #[test]
+    fn full_buffer_drops_the_oldest() {
+        let mut dmesg = dmesg();
+        for i in 0..DMESG_SIZE / 8 + 3 {
+            writeln!(dmesg, "{:07}", i).unwrap();
+        }
+        assert_eq!(dmesg.len, DMESG_SIZE);
+        // The three oldest lines are overwritten, the buffer starts with the fourth one.
+        let mut buf = [0; DMESG_SIZE];
+        assert_eq!(dmesg.read(0, &mut buf), DMESG_SIZE);
+        assert_eq!(&buf[..8], b"0000003\n");
+        let last = format!("{:07}\n", DMESG_SIZE / 8 + 2);
+        assert_eq!(&buf[DMESG_SIZE - 8..], last.as_bytes());
+        // Reads wrap around the end of the array.
+        let mut tail = [0; 8];
+        assert_eq!(dmesg.read(DMESG_SIZE - 8, &mut tail), 8);
+        assert_eq!(&tail, last.as_bytes());
+    }
+
+    #[test]
+    fn long_line_is_cut() {
+        let mut buf = line();
+        write!(buf, "{}", "x".repeat(2 * MAX_LINE)).unwrap();
+        let text = buf.finish();
+        assert_eq!(text.len(), MAX_LINE);
+        assert!(text.ends_with("x\n"));
+        // Nothing is added behind the cut.
+        let mut buf = line();
+        write!(buf, "{}more", "x".repeat(MAX_LINE)).unwrap();
+        assert!(!buf.finish().contains("more"));
+    }
+
+    #[test]
+    fn cut_keeps_whole_chars() {
+        let mut buf = line();
+        // The last char would be split by the cut.
+        write!(buf, "{}ä", "x".repeat(MAX_LINE - 2)).unwrap();
+        assert_eq!(buf.finish(), format!("{}\n", "x".repeat(MAX_LINE - 2)));
+        let mut buf = line();
+        buf.write_str("short\n").unwrap();
+        assert_eq!(buf.finish(), "short\n");
+    }
+}
@@ -6,15 +6,16 @@
 /// print!("Hello world");
 /// let world = "world";
 /// print!("Hello {}", world);
-/// print!("{}", uart::read_char());
+/// let char = uart::read_byte().unwrap() as char;
+/// print!("{}", char);
 /// 
 /// The output should be on input of "Test":
 /// Hello worldHello worldTest
 #[allow(unused)]
 macro_rules! print {
-    ($($arg:tt)*) => {
+    ($($arg:tt)*) => {{
         use core::fmt::Write;
-        write!(crate::hardware::uart::get_uart(), $($arg)*).ok()}
+        write!(crate::hardware::uart::get_uart(), $($arg)*).ok()}}
 }
 #[allow(unused)]
 pub(crate) use print;
@@ -27,7 +28,8 @@ pub(crate) use print;
 /// println!("Hello world");
 /// let world = "world";
 /// println!("Hello {}", world);
-/// println!("{}", uart::read_char());
+/// let char = uart::read_byte().unwrap() as char;
+/// println!("{}", char);
 /// 
 /// The output should be on input of "Test":
 /// Hello world
@@ -35,14 +37,75 @@ pub(crate) use print;
 /// Test
 #[allow(unused)]
 macro_rules! println {
-    ($($arg:tt)*) => {
-        crate::hardware::uart::print_char('\n');
+    ($($arg:tt)*) => {{
         use core::fmt::Write;
-        write!(crate::hardware::uart::get_uart(), $($arg)*).ok()}
+        // One lock for both, so the lines of the harts are not mixed
+        let mut uart = crate::hardware::uart::get_uart();
+        write!(uart, $($arg)*).ok();
+        uart.write_char('\n').ok()}}
 }
 #[allow(unused)]
 pub(crate) use println;
 
+/// The macro log!() takes a level of `crate::log::Level` and a string with arguments like println!().
+/// The line is printed with a timestamp, the level and the module and kept in the kernel log.
+/// It is left out if the level is above the compile time or the runtime level of `crate::log`.
+///
+/// For example:
+/// log!(Level::Info, "Hart {} started", hart_id);
+///
+/// The output of hart 1 started 0.1 s after boot in main.rs:
+/// [    0.100000] INFO  riscv_rust_os: Hart 1 started
+#[allow(unused)]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {{
+        let level = $level;
+        if level <= crate::log::STATIC_MAX_LEVEL {
+            crate::log::write(level, module_path!(), format_args!($($arg)*));
+        }}}
+}
+#[allow(unused)]
+pub(crate) use log;
+
+/// The macros error!(), warn!(), info!(), debug!() and trace!() call log!() with their level.
+///
+/// For example:
+/// warn!("Unknown log level: {}", level);
+#[allow(unused)]
+macro_rules! error {
+    ($($arg:tt)*) => { crate::log!(crate::log::Level::Error, $($arg)*) }
+}
+#[allow(unused)]
+pub(crate) use error;
+
+// Exported at the crate root instead, a `use` of it is ambiguous with the built-in attribute.
+#[allow(unused)]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { crate::log!(crate::log::Level::Warn, $($arg)*) }
+}
+
+#[allow(unused)]
+macro_rules! info {
+    ($($arg:tt)*) => { crate::log!(crate::log::Level::Info, $($arg)*) }
+}
+#[allow(unused)]
+pub(crate) use info;
+
+#[allow(unused)]
+macro_rules! debug {
+    ($($arg:tt)*) => { crate::log!(crate::log::Level::Debug, $($arg)*) }
+}
+#[allow(unused)]
+pub(crate) use debug;
+
+#[allow(unused)]
+macro_rules! trace {
+    ($($arg:tt)*) => { crate::log!(crate::log::Level::Trace, $($arg)*) }
+}
+#[allow(unused)]
+pub(crate) use trace;
+
 /// The macro enum_matching!() gets a number and an enumeration (enum) as input. 
 /// It checks whether the number is the enum. 
 /// If it is, the enum is returned.
@@ -1,47 +1,116 @@
-#![no_std]
-#![no_main]
+// The unit tests run on the host with the test harness of std, see the README.
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Most of the kernel is left unused by the tests.
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 mod asm;
+mod cpu;
 mod exception_handler;
+mod fdt;
+#[cfg(feature = "builtin-sbi")]
+mod firmware;
+mod gdb;
 mod hardware;
+mod ipi;
+mod kmem;
+mod log;
 mod macros;
 mod mmu;
 mod page;
 mod panic_handler;
+mod perf;
+mod platform;
+#[cfg(feature = "sbi")]
+mod sbi;
 mod scheduler;
+mod selftest;
 mod setup;
+mod smp;
+mod symbols;
+mod sync;
 mod system_calls;
-mod uart;
 mod user_prog;
+mod vma;
 
 pub(crate) use macros::*;
 
-//TODO implement shutdown
-fn _shutdown() {}
+/// Turns off the machine with the test device of qemu, a `code` other than 0 reports a failure.
+pub fn shutdown(code: u16) -> ! {
+    unsafe { hardware::uart::flush() };
+    // OpenSBI may keep the device to itself, it reports every code other than 0 as 1.
+    #[cfg(all(feature = "sbi", not(feature = "builtin-sbi")))]
+    {
+        let reason = match code {
+            0 => sbi::ResetReason::None,
+            _ => sbi::ResetReason::SystemFailure,
+        };
+        sbi::system_reset(sbi::ResetType::Shutdown, reason);
+    }
+    unsafe { hardware::syscon::poweroff(code) };
+    loop {}
+}
+
+/// Resets the machine with the test device of qemu.
+pub fn reboot() -> ! {
+    unsafe { hardware::uart::flush() };
+    #[cfg(all(feature = "sbi", not(feature = "builtin-sbi")))]
+    sbi::system_reset(sbi::ResetType::ColdReboot, sbi::ResetReason::None);
+    unsafe { hardware::syscon::reboot() };
+    loop {}
+}
 
-#[no_mangle]
-unsafe extern "C" fn kernel_setup() {
-    // Initalize the heap
-    page::PageAllocator::init_heap();
+#[cfg_attr(not(test), no_mangle)]
+unsafe extern "C" fn kernel_setup(hart_id: usize, dtb: usize) {
+    if !smp::is_boot_hart(hart_id) {
+        // Wait until the boot hart has set up the kernel, then run the user progs with it
+        smp::wait_for_release();
+        smp::lock();
+        kmem::init_hart();
+        setup::setup_hart();
+        smp::set_online();
+        info!("Hart {} started", hart_id);
+        scheduler::idle();
+    }
+    smp::lock();
+    // Find the memory and the devices, before anything uses them
+    platform::init(dtb);
+    hardware::uart::set_base(platform::get().uart);
+    log::init();
+    platform::print();
+    perf::init();
+    // Initalize the page allocator, the device tree stays where qemu put it
+    page::init();
+    if let Some((addr, size)) = platform::get().dtb {
+        page::reserve(addr, addr + size);
+    }
+    // Map the kernel, set up the trap frame and turn on paging
+    kmem::init();
+    kmem::init_hart();
+    // Check the memory code, before the user progs rely on it
+    if cfg!(debug_assertions) {
+        selftest::run();
+    }
     setup::setup();
 
     // switch to user mode (configured in mstatus) and jump to address in mepc CSR -> main().
 
-    // Initialize the UART writer with the appropriate base address
-    uart::print_os();
+    // The uart is set up once by setup::setup, everything printed from here on goes through its driver
+    hardware::uart::print_os();
 
     // Test the print and println functions
     //println!("Hello, world!");
     //print!("Cycle count: {}\n", mcycle::read());
 
-    // Allocate a page
-    let mut allocator = page::PageAllocator::new();
-    // The MMU
-    let mut manager = mmu::PageTableManager::new();
-    let virtual_address = 0xdeadbeefusize;
-    let physical_address = allocator.allocate_kernel_page();
-    // The rest of the program
     let user1 = scheduler::init_prog(user_prog::USER1);
     scheduler::init_prog(user_prog::USER2);
+    // strace is optional, it is started if the debugger loaded it as well.
+    if user_prog::STRACE.is_loaded() {
+        scheduler::init_prog(user_prog::STRACE);
+    }
+    // With `gdb` in the boot arguments, the user progs start once gdb continues them.
+    gdb::init();
+    smp::set_online();
+    smp::release(dtb);
     scheduler::boot_prog(user1);
 }
@@ -1,223 +1,218 @@
-#![feature(asm)]
+use crate::page;
 
-use core::arch::asm;
-const PAGE_SIZE: usize = 4096;
 const PAGE_TABLE_ENTRIES: usize = 512;
-#[repr(u8)]
-#[derive(Clone)]
-enum PageTableEntryFlags {
-    VALID = 0b1,
-    READABLE = 0b10,
-    WRITABLE = 0b100,
-    EXECUTABLE = 0b1000,
-    USER = 0b10000,
-}
 
+/// The bits of a Sv39 page table entry.
+#[repr(usize)]
 #[derive(Clone, Copy)]
-struct PageTableEntry {
-    entry: usize,
+pub enum EntryBits {
+    None = 0,
+    Valid = 1 << 0,
+    Read = 1 << 1,
+    Write = 1 << 2,
+    Execute = 1 << 3,
+    User = 1 << 4,
+    Global = 1 << 5,
+    Access = 1 << 6,
+    Dirty = 1 << 7,
+
+    // For convenience
+    ReadWrite = 1 << 1 | 1 << 2,
+    ReadExecute = 1 << 1 | 1 << 3,
+    ReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3,
+    // User convenience
+    UserReadWrite = 1 << 1 | 1 << 2 | 1 << 4,
+    UserReadExecute = 1 << 1 | 1 << 3 | 1 << 4,
+    UserReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4,
 }
 
-impl PageTableEntry {
-    fn is_valid(&self) -> bool {
-        (self.entry & PageTableEntryFlags::VALID as usize) != 0
-    }
-    fn set_valid(&mut self, valid: bool) {
-        if valid {
-            self.entry |= PageTableEntryFlags::VALID as usize;
-        } else {
-            self.entry &= !(PageTableEntryFlags::VALID as usize);
-        }
+impl EntryBits {
+    pub fn val(self) -> usize {
+        self as usize
     }
+}
 
-    fn is_readable(&self) -> bool {
-        (self.entry & PageTableEntryFlags::READABLE as usize) != 0
-    }
+#[derive(Clone, Copy)]
+pub struct Entry {
+    entry: usize,
+}
 
-    fn set_readable(&mut self, readable: bool) {
-        if readable {
-            self.entry |= PageTableEntryFlags::READABLE as usize;
-        } else {
-            self.entry &= !(PageTableEntryFlags::READABLE as usize);
-        }
+impl Entry {
+    pub fn is_valid(&self) -> bool {
+        self.entry & EntryBits::Valid.val() != 0
     }
 
-    fn is_writable(&self) -> bool {
-        (self.entry & PageTableEntryFlags::WRITABLE as usize) != 0
+    pub fn is_invalid(&self) -> bool {
+        !self.is_valid()
     }
 
-    fn set_writable(&mut self, writable: bool) {
-        if writable {
-            self.entry |= PageTableEntryFlags::WRITABLE as usize;
-        } else {
-            self.entry &= !(PageTableEntryFlags::WRITABLE as usize);
-        }
+    /// A leaf has at least one of the R, W, X bits set. Otherwise it points to the next table.
+    pub fn is_leaf(&self) -> bool {
+        self.entry & EntryBits::ReadWriteExecute.val() != 0
     }
 
-    fn is_executable(&self) -> bool {
-        (self.entry & PageTableEntryFlags::EXECUTABLE as usize) != 0
+    pub fn is_branch(&self) -> bool {
+        !self.is_leaf()
     }
 
-    fn set_executable(&mut self, executable: bool) {
-        if executable {
-            self.entry |= PageTableEntryFlags::EXECUTABLE as usize;
-        } else {
-            self.entry &= !(PageTableEntryFlags::EXECUTABLE as usize);
-        }
+    pub fn get_entry(&self) -> usize {
+        self.entry
     }
 
-    fn is_user(&self) -> bool {
-        (self.entry & PageTableEntryFlags::USER as usize) != 0
+    pub fn set_entry(&mut self, entry: usize) {
+        self.entry = entry;
     }
 
-    fn set_user(&mut self, user: bool) {
-        if user {
-            self.entry |= PageTableEntryFlags::USER as usize;
-        } else {
-            self.entry &= !(PageTableEntryFlags::USER as usize);
-        }
+    /// Returns the physical address the entry points to.
+    pub fn get_physical_address(&self) -> usize {
+        (self.entry & !0x3ff) << 2
     }
+}
 
-    fn get_physical_address(&self) -> usize {
-        self.entry & !(PageTableEntryFlags::VALID as usize)
-    }
+#[repr(C)]
+pub struct Table {
+    pub entries: [Entry; PAGE_TABLE_ENTRIES],
+}
 
-    fn set_physical_address(&mut self, physical_address: usize) {
-        self.entry = physical_address | (self.entry & (PageTableEntryFlags::VALID as usize));
+impl Table {
+    pub const fn len() -> usize {
+        PAGE_TABLE_ENTRIES
     }
 }
 
-struct PageTable {
-    entries: [PageTableEntry; PAGE_TABLE_ENTRIES],
+/// Splits a virtual address into its three virtual page numbers.
+fn vpn(vaddr: usize) -> [usize; 3] {
+    [
+        (vaddr >> 12) & 0x1ff,
+        (vaddr >> 21) & 0x1ff,
+        (vaddr >> 30) & 0x1ff,
+    ]
 }
 
-impl PageTable {
-    fn new() -> Self {
-        Self {
-            entries: [PageTableEntry { entry: 0 }; PAGE_TABLE_ENTRIES],
-        }
-    }
-    fn get_entry(&self, vpn: usize) -> Option<&PageTableEntry> {
-        if vpn < PAGE_TABLE_ENTRIES {
-            let entry = &self.entries[vpn];
-            if entry.is_valid() {
-                Some(entry)
-            } else {
-                None
-            }
-        } else {
-            None
+/// Maps `vaddr` to `paddr` with the given `bits`.
+/// The `level` selects the page size: 0 = 4 KiB, 1 = 2 MiB, 2 = 1 GiB.
+/// Missing tables are allocated with the page allocator.
+pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: usize, level: usize) {
+    assert!(
+        bits & EntryBits::ReadWriteExecute.val() != 0,
+        "Tried to map 0x{:x} without R, W or X bits",
+        vaddr
+    );
+    let vpn = vpn(vaddr);
+    let ppn = [
+        (paddr >> 12) & 0x1ff,
+        (paddr >> 21) & 0x1ff,
+        (paddr >> 30) & 0x3ff_ffff,
+    ];
+
+    let mut v = &mut root.entries[vpn[2]];
+    for i in (level..2).rev() {
+        if v.is_invalid() {
+            let page = page::zalloc(1);
+            assert!(!page.is_null(), "Out of memory for page tables");
+            v.set_entry((page as usize >> 2) | EntryBits::Valid.val());
         }
-    }
+        let entry = v.get_physical_address() as *mut Entry;
+        v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+    }
+
+    // The accessed and dirty bits are set up front, so the hardware never has to update them.
+    let entry = (ppn[2] << 28)
+        | (ppn[1] << 19)
+        | (ppn[0] << 10)
+        | bits
+        | EntryBits::Valid.val()
+        | EntryBits::Access.val()
+        | EntryBits::Dirty.val();
+    v.set_entry(entry);
+}
 
-    fn get_entry_mut(&mut self, vpn: usize) -> Option<&mut PageTableEntry> {
-        if vpn < PAGE_TABLE_ENTRIES {
-            let entry = &mut self.entries[vpn];
-            if entry.is_valid() {
-                Some(entry)
-            } else {
-                None
+/// Frees all tables below the `root` table. The root table itself and the mapped pages are not freed.
+pub fn unmap(root: &mut Table) {
+    for lv2 in 0..Table::len() {
+        let entry_lv2 = root.entries[lv2];
+        if entry_lv2.is_valid() && entry_lv2.is_branch() {
+            let memaddr_lv1 = entry_lv2.get_physical_address();
+            let table_lv1 = unsafe { (memaddr_lv1 as *mut Table).as_mut().unwrap() };
+            for lv1 in 0..Table::len() {
+                let entry_lv1 = table_lv1.entries[lv1];
+                if entry_lv1.is_valid() && entry_lv1.is_branch() {
+                    page::dealloc(entry_lv1.get_physical_address() as *mut u8);
+                }
             }
-        } else {
-            None
+            page::dealloc(memaddr_lv1 as *mut u8);
         }
+        root.entries[lv2].set_entry(0);
     }
 }
-pub struct PageTableManager {
-    root_table: PageTable,
-}
 
-impl PageTableManager {
-    pub fn new() -> Self {
-        Self {
-            root_table: PageTable::new(),
-        }
-    }
-
-    fn map_page(&mut self, virtual_address: usize) {
-        let ppn = allocate_page() >> 12;
-
-        let vpn = virtual_address >> 12;
-        let root_entry = self.root_table.get_entry_mut(vpn >> 9).unwrap();
-        let table_address = root_entry.get_physical_address();
-        let table = unsafe { &mut *(table_address as *mut PageTable) };
-
-        let entry = table.get_entry_mut(vpn & 0x1ff).unwrap();
+/// Walks the page table and returns the physical address `vaddr` is mapped to.
+pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
+    let (entry, level) = leaf(root, vaddr)?;
+    let off_mask = (1 << (12 + level * 9)) - 1;
+    let vaddr_pgoff = vaddr & off_mask;
+    let addr = entry.get_physical_address() & !off_mask;
+    Some(addr | vaddr_pgoff)
+}
 
-        entry.set_physical_address(
-            (ppn << 12)
-                | PageTableEntryFlags::VALID as usize
-                | PageTableEntryFlags::READABLE as usize
-                | PageTableEntryFlags::WRITABLE as usize,
-        );
+/// Returns the bits of the entry mapping `vaddr`, if it is mapped.
+pub fn entry_bits(root: &Table, vaddr: usize) -> Option<usize> {
+    leaf(root, vaddr).map(|(entry, _)| entry.get_entry() & 0x3ff)
+}
 
-        unsafe {
-            asm!("sfence.vma {}", in(reg) virtual_address);
+/// Returns the leaf entry mapping `vaddr` and its level.
+fn leaf(root: &Table, vaddr: usize) -> Option<(Entry, usize)> {
+    let vpn = vpn(vaddr);
+    let mut v = &root.entries[vpn[2]];
+    for i in (0..=2).rev() {
+        if v.is_invalid() {
+            break;
+        } else if v.is_leaf() {
+            return Some((*v, i));
+        } else if i == 0 {
+            // A branch on the last level is an invalid entry.
+            break;
         }
+        let entry = v.get_physical_address() as *const Entry;
+        v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
     }
+    None
+}
 
-    fn unmap_page(&mut self, virtual_address: usize) {
-        let vpn = virtual_address >> 12;
-
-        let root_entry = self.root_table.get_entry_mut(vpn >> 9).unwrap();
-
-        let table_address = root_entry.get_physical_address();
-        let table = unsafe { &mut *(table_address as *mut PageTable) };
-
-        let entry = table.get_entry_mut(vpn & 0x1ff).unwrap();
-        // set the entry as invalid
-        entry.set_valid(false);
+/// Calls `f` with the virtual address and the entry of each 4 KiB page mapped from `start` to `end`.
+/// Only the present tables are walked, a large range with few mapped pages is cheap.
+pub fn for_each_page(
+    root: &mut Table,
+    start: usize,
+    end: usize,
+    f: &mut impl FnMut(usize, &mut Entry),
+) {
+    walk(root, 2, 0, start, end, f);
+}
 
-        unsafe {
-            asm!("sfence.vma {}", in(reg) virtual_address);
+fn walk(
+    table: &mut Table,
+    level: usize,
+    base: usize,
+    start: usize,
+    end: usize,
+    f: &mut impl FnMut(usize, &mut Entry),
+) {
+    let size = 1 << (12 + level * 9);
+    for (i, entry) in table.entries.iter_mut().enumerate() {
+        let vaddr = base + i * size;
+        if vaddr + size <= start || vaddr >= end || entry.is_invalid() {
+            continue;
         }
-    }
-    fn translate(&self, virtual_address: usize) -> Option<usize> {
-        let vpn = virtual_address >> 12;
-        let root_entry = self.root_table.get_entry(vpn >> 9)?;
-        let table_address = root_entry.get_physical_address();
-        let table = unsafe { &*(table_address as *const PageTable) };
-
-        let entry = table.get_entry(vpn & 0x1ff)?;
-
-        if !entry.is_valid() {
-            return None;
+        if level == 0 {
+            f(vaddr, entry);
+        } else if entry.is_branch() {
+            let next = unsafe {
+                (entry.get_physical_address() as *mut Table)
+                    .as_mut()
+                    .unwrap()
+            };
+            walk(next, level - 1, vaddr, start, end, f);
         }
-        let physical_address = entry.get_physical_address();
-
-        Some((physical_address << 12) | (virtual_address & 0xfff))
     }
 }
-
-// Function to allocate a page
-fn allocate_page() -> usize {
-    // Code to allocate a page
-    // ...
-    0 // Placeholder value
-}
-
-/*
-// Enable MMU in mstatus register
-let mut mstatus: usize;
-unsafe { llvm_asm!("csrr $0, mstatus" : "=r"(mstatus) ::: "volatile") };
-mstatus |= 1 << 3; // set bit 3 to enable MMU
-unsafe { llvm_asm!("csrw mstatus, $0" :: "r"(mstatus) :: "volatile") };
-
-
-// Set up page table for kernel
-let page_table = // pointer to page table
-
-// Set the satp register to enable paging
-let satp: usize = (8 << 60) | (page_table >> 12);
-unsafe { llvm_asm!("csrw satp, $0" :: "r"(satp) :: "volatile") };
-
-// Set sscratch to point to the supervisor stack
-let stack_top = // top of the supervisor stack
-unsafe { llvm_asm!("mv sscratch, $0" :: "r"(stack_top) :: "volatile") };
-
-// Set sepc to the entry point of the kernel
-let kernel_entry_point = // entry point of the kernel
-unsafe { llvm_asm!("mv sepc, $0" :: "r"(kernel_entry_point) :: "volatile") };
-
-// Enable supervisor mode
-unsafe { llvm_asm!("mret") }; */
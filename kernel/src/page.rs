@@ -1,150 +1,253 @@
-const PAGE_SIZE: usize = 4096;
-const MEM_SIZE: usize = 128 * 1024 * 1024;
+use core::{mem::size_of, ptr::null_mut, slice};
 
-const KERNEL_PAGES: usize = 16384;
-const BITMAP_SIZE: usize = (MEM_SIZE / PAGE_SIZE + 7) / 8;
+use crate::{platform, sync::SpinLock};
 
-static mut ALLOC_START: usize = 0;
-static mut ALLOC_END: usize = 0;
+// Linker symbols exported by mem.S
+extern "C" {
+    static HEAP_START: usize;
+}
 
-pub struct PageAllocator {
-    kernel_bitmap: [u8; BITMAP_SIZE],
-    user_bitmap: [u8; BITMAP_SIZE],
-    kernel_end: usize,
-    user_end: usize,
-    allocated_kernel_pages: [usize; KERNEL_PAGES],
-    allocated_user_pages: [usize; MEM_SIZE / PAGE_SIZE - KERNEL_PAGES],
-    kernel_pages_count: usize,
-    user_pages_count: usize,
+/// The allocatable pages, set up by [init].
+static HEAP: SpinLock<Heap> = SpinLock::new(Heap {
+    alloc_start: 0,
+    alloc_pages: 0,
+    end: 0,
+});
+const PAGE_ORDER: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_ORDER;
+
+/// Rounds `value` up to the next multiple of `2^order`.
+pub const fn align_val(value: usize, order: usize) -> usize {
+    let o = (1usize << order) - 1;
+    (value + o) & !o
 }
 
-impl PageAllocator {
-    pub fn new() -> Self {
-        Self {
-            kernel_bitmap: [0; BITMAP_SIZE],
-            user_bitmap: [0; BITMAP_SIZE],
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum PageBits {
+    Empty = 0,
+    Taken = 1 << 0,
+    Last = 1 << 1,
+}
 
-            kernel_end: KERNEL_PAGES * PAGE_SIZE,
-            user_end: 0,
+impl PageBits {
+    pub fn val(self) -> u8 {
+        self as u8
+    }
+}
 
-            allocated_kernel_pages: [0; KERNEL_PAGES],
-            allocated_user_pages: [0; MEM_SIZE / PAGE_SIZE - KERNEL_PAGES],
+/// Descriptor of one allocatable page. The descriptors are stored at the start of the heap,
+/// the pages themselves start at [Heap::alloc_start].
+pub struct Page {
+    flags: u8,
+    /// Number of additional owners of a shared page, see [share].
+    refs: u8,
+}
 
-            kernel_pages_count: 0,
-            user_pages_count: 0,
-        }
+impl Page {
+    pub fn is_last(&self) -> bool {
+        self.flags & PageBits::Last.val() != 0
     }
 
-    fn allocate_page(&mut self, kernel: bool) -> Option<usize> {
-        let start = if kernel { 0 } else { self.kernel_end };
-        let end = if kernel { self.kernel_end } else { MEM_SIZE };
+    pub fn is_taken(&self) -> bool {
+        self.flags & PageBits::Taken.val() != 0
+    }
 
-        let bitmap = if kernel {
-            &mut self.kernel_bitmap
-        } else {
-            &mut self.user_bitmap
-        };
+    pub fn is_free(&self) -> bool {
+        !self.is_taken()
+    }
 
-        for i in (start / PAGE_SIZE / 8)..(end / PAGE_SIZE / 8) {
-            if bitmap[i] != 0xff {
-                let free_bit_index = bitmap[i].trailing_zeros() as usize;
+    pub fn clear(&mut self) {
+        self.flags = PageBits::Empty.val();
+        self.refs = 0;
+    }
 
-                let page_index = i * 8 + free_bit_index;
+    pub fn set_flag(&mut self, flag: PageBits) {
+        self.flags |= flag.val();
+    }
+}
 
-                bitmap[i] |= 1 << free_bit_index;
+struct Heap {
+    /// Start of the first allocatable page, behind the page descriptors.
+    alloc_start: usize,
+    /// Number of allocatable pages.
+    alloc_pages: usize,
+    /// The heap reaches from `HEAP_START` to the end of the memory found by [platform::init].
+    end: usize,
+}
 
-                if !kernel && page_index * PAGE_SIZE > self.user_end {
-                    self.user_end = page_index * PAGE_SIZE;
-                }
+impl Heap {
+    /// Returns the descriptors of the allocatable pages.
+    fn pages(&mut self) -> &mut [Page] {
+        unsafe { slice::from_raw_parts_mut(HEAP_START as *mut Page, self.alloc_pages) }
+    }
 
-                if kernel {
-                    self.allocated_kernel_pages[self.kernel_pages_count] = page_index * PAGE_SIZE;
-                    self.kernel_pages_count += 1;
-                } else {
-                    self.allocated_user_pages[self.user_pages_count] = page_index * PAGE_SIZE;
-                    self.user_pages_count += 1;
-                }
+    fn owns(&self, addr: usize) -> bool {
+        addr >= self.alloc_start && addr < self.alloc_start + self.alloc_pages * PAGE_SIZE
+    }
 
-                return Some(page_index * PAGE_SIZE);
-            }
-        }
-        // No free page was found
-        None
-    }
-
-    fn free_page(&mut self, phys_addr: usize, kernel: bool) {
-        let bitmap = if kernel {
-            &mut self.kernel_bitmap
-        } else {
-            &mut self.user_bitmap
-        };
-        // Mark the page as free in the bitmap
-        let page_index = phys_addr / PAGE_SIZE;
-        let bitmap_index = page_index / 8;
-        let bit_index = page_index % 8;
-        bitmap[bitmap_index] &= !(1 << bit_index);
-        // Update the end address if necessary
-        if kernel && phys_addr >= self.kernel_end && phys_addr < self.user_end {
-            self.user_end = self.kernel_end;
-        } else if !kernel && phys_addr >= self.user_end {
-            self.user_end = phys_addr;
-        }
-        // Remove the physical address from the list of allocated pages
-        if kernel {
-            let mut i = 0;
-            while i < self.kernel_pages_count {
-                if self.allocated_kernel_pages[i] == phys_addr {
-                    break;
-                }
-                i += 1;
-            }
-            while i < self.kernel_pages_count - 1 {
-                self.allocated_kernel_pages[i] = self.allocated_kernel_pages[i + 1];
-                i += 1;
-            }
-            self.allocated_kernel_pages[self.kernel_pages_count - 1] = 0;
-            self.kernel_pages_count -= 1;
-        } else {
-            let mut i = 0;
-            while i < self.user_pages_count {
-                if self.allocated_user_pages[i] == phys_addr {
-                    break;
+    /// Returns the descriptor of the allocated page at `addr`.
+    fn descriptor(&mut self, addr: usize) -> &mut Page {
+        assert!(self.owns(addr), "0x{:x} is not an allocated page", addr);
+        let idx = (addr - self.alloc_start) / PAGE_SIZE;
+        &mut self.pages()[idx]
+    }
+
+    fn alloc(&mut self, pages: usize) -> *mut u8 {
+        let alloc_start = self.alloc_start;
+        let descriptors = self.pages();
+        let mut i = 0;
+        while i + pages <= descriptors.len() {
+            match (i..i + pages).find(|&j| descriptors[j].is_taken()) {
+                // Continue searching behind the taken page.
+                Some(taken) => i = taken + 1,
+                None => {
+                    for page in &mut descriptors[i..i + pages] {
+                        page.set_flag(PageBits::Taken);
+                    }
+                    descriptors[i + pages - 1].set_flag(PageBits::Last);
+                    return (alloc_start + PAGE_SIZE * i) as *mut u8;
                 }
-                i += 1;
-            }
-            while i < self.user_pages_count - 1 {
-                self.allocated_user_pages[i] = self.allocated_user_pages[i + 1];
-                i += 1;
             }
-            self.allocated_user_pages[self.user_pages_count - 1] = 0;
-            self.user_pages_count -= 1;
         }
-    }
-    pub fn allocate_kernel_page(&mut self) -> Option<usize> {
-        self.allocate_page(true)
+        null_mut()
     }
 
-    pub fn allocate_user_page(&mut self) -> Option<usize> {
-        self.allocate_page(false)
+    fn dealloc(&mut self, ptr: *mut u8) {
+        assert!(
+            self.owns(ptr as usize),
+            "Tried to free a page outside of the heap"
+        );
+        let first = (ptr as usize - self.alloc_start) / PAGE_SIZE;
+        let descriptors = self.pages();
+        let mut idx = first;
+        while descriptors[idx].is_taken() && !descriptors[idx].is_last() {
+            descriptors[idx].clear();
+            idx += 1;
+        }
+        let last = &mut descriptors[idx];
+        assert!(
+            last.is_last(),
+            "Possible double free at 0x{:x}",
+            ptr as usize
+        );
+        assert!(
+            last.refs == 0,
+            "Tried to free the shared page 0x{:x}",
+            ptr as usize
+        );
+        last.clear();
     }
+}
 
-    pub fn free_kernel_page(&mut self, phys_addr: usize) {
-        self.free_page(phys_addr, true)
+/// Clears all page descriptors. Has to be called before any other function of this module.
+pub fn init() {
+    let mut heap = HEAP.lock();
+    unsafe {
+        heap.end = platform::get().memory_end & !(PAGE_SIZE - 1);
+        assert!(heap.end > HEAP_START, "No memory left for the heap");
+        let num_pages = (heap.end - HEAP_START) / PAGE_SIZE;
+        let ptr = HEAP_START as *mut Page;
+        for i in 0..num_pages {
+            (*ptr.add(i)).clear();
+        }
+        heap.alloc_start = align_val(HEAP_START + num_pages * size_of::<Page>(), PAGE_ORDER);
     }
+    heap.alloc_pages = (heap.end - heap.alloc_start) / PAGE_SIZE;
+}
+
+/// Returns the end of the heap.
+pub fn heap_end() -> usize {
+    HEAP.lock().end
+}
 
-    pub fn free_user_page(&mut self, phys_addr: usize) {
-        self.free_page(phys_addr, false)
+/// Marks the pages from `start` to `end` as taken, so they are never handed out.
+/// The parts outside of the allocatable pages are ignored.
+pub fn reserve(start: usize, end: usize) {
+    let mut heap = HEAP.lock();
+    let alloc_start = heap.alloc_start;
+    let first = start.max(alloc_start) & !(PAGE_SIZE - 1);
+    let last = align_val(end, PAGE_ORDER).min(alloc_start + heap.alloc_pages * PAGE_SIZE);
+    if first >= last {
+        return;
+    }
+    let (first, last) = (
+        (first - alloc_start) / PAGE_SIZE,
+        (last - alloc_start) / PAGE_SIZE,
+    );
+    let descriptors = heap.pages();
+    for page in &mut descriptors[first..last] {
+        assert!(page.is_free(), "Reserved a taken page");
+        page.set_flag(PageBits::Taken);
     }
+    descriptors[last - 1].set_flag(PageBits::Last);
+}
 
-    #[no_mangle]
-    pub extern "C" fn init_heap() {
-        let kernel_end = KERNEL_PAGES * PAGE_SIZE;
-        let heap_start = ((kernel_end - 1) / PAGE_SIZE + 1) * PAGE_SIZE;
-        let heap_end = (MEM_SIZE / PAGE_SIZE) * PAGE_SIZE - PAGE_SIZE;
+/// Allocates `pages` contiguous pages. Returns a null pointer if there is no space left.
+pub fn alloc(pages: usize) -> *mut u8 {
+    assert!(pages > 0);
+    HEAP.lock().alloc(pages)
+}
 
-        unsafe {
-            ALLOC_START = heap_start;
-            ALLOC_END = heap_end;
+/// Allocates `pages` contiguous pages and fills them with zeros.
+pub fn zalloc(pages: usize) -> *mut u8 {
+    let ret = alloc(pages);
+    if !ret.is_null() {
+        let size = (PAGE_SIZE * pages) / 8;
+        let big_ptr = ret as *mut u64;
+        for i in 0..size {
+            unsafe {
+                big_ptr.add(i).write(0);
+            }
         }
     }
+    ret
+}
+
+/// Frees the allocation starting at `ptr`, which has to be returned by [alloc] or [zalloc].
+pub fn dealloc(ptr: *mut u8) {
+    assert!(!ptr.is_null());
+    HEAP.lock().dealloc(ptr);
+}
+
+/// Returns true if `addr` lies in a page handed out by the allocator.
+pub fn owns(addr: usize) -> bool {
+    HEAP.lock().owns(addr)
+}
+
+/// Adds an owner to the single page at `addr`. It is freed after every owner called [release].
+pub fn share(addr: usize) {
+    let mut heap = HEAP.lock();
+    let page = heap.descriptor(addr);
+    assert!(
+        page.is_taken(),
+        "Tried to share a free page at 0x{:x}",
+        addr
+    );
+    page.refs = page.refs.checked_add(1).expect("Page shared too often");
+}
+
+/// Returns true if the page at `addr` has more than one owner.
+pub fn is_shared(addr: usize) -> bool {
+    HEAP.lock().descriptor(addr).refs != 0
+}
+
+/// Drops one owner of the single page at `addr` and frees the page if it was the last one.
+pub fn release(addr: usize) {
+    let mut heap = HEAP.lock();
+    let page = heap.descriptor(addr);
+    if page.refs != 0 {
+        page.refs -= 1;
+    } else {
+        heap.dealloc(addr as *mut u8);
+    }
+}
+
+/// Returns the number of free pages.
+pub fn free_pages() -> usize {
+    HEAP.lock()
+        .pages()
+        .iter()
+        .filter(|page| page.is_free())
+        .count()
 }
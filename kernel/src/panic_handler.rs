@@ -1,9 +1,100 @@
-use crate::hardware::uart;
+use crate::cpu;
+use crate::hardware::uart::{self, UART};
+use crate::{platform, scheduler, symbols};
 use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-#[panic_handler]
+/// Frames printed at most, a broken chain of frame pointers could go on forever.
+const MAX_FRAMES: usize = 32;
+const NO_HART: usize = usize::MAX;
+
+/// The hart that panicked first.
+static PANIC_HART: AtomicUsize = AtomicUsize::new(NO_HART);
+
+#[cfg_attr(not(test), panic_handler)]
 unsafe fn panic(info: &core::panic::PanicInfo) -> ! {
-    uart::print_str("\n\n\n### System Crash ###\n");
-    write!(uart::get_uart(), "{}", info).ok();
-    loop {}
+    cpu::disable_interrupts();
+    let hart_id = cpu::hart_id();
+    if let Err(hart) =
+        PANIC_HART.compare_exchange(NO_HART, hart_id, Ordering::AcqRel, Ordering::Acquire)
+    {
+        if hart == hart_id {
+            // The report itself panicked.
+            crate::shutdown(1);
+        }
+        // Another hart reports its panic and stops this one.
+        loop {
+            cpu::wait_for_interrupt();
+        }
+    }
+    // Stop the other harts, the kernel state can't be trusted anymore.
+    crate::ipi::send_others(crate::ipi::Ipi::Halt);
+    // The lock is not taken, the panic may have happened while it was held.
+    let uart = uart::force_get_uart();
+    writeln!(uart, "\n\n\n### System Crash ###\n{}", info).ok();
+    match scheduler::force_cur() {
+        Some((id, pid)) => writeln!(uart, "Hart {}, user prog {:?} (pid {})", hart_id, id, pid),
+        None => writeln!(uart, "Hart {}, no user prog", hart_id),
+    }
+    .ok();
+    dump_trap(uart);
+    backtrace(uart);
+    // Let automated runs end with a failure instead of hanging.
+    crate::shutdown(1)
+}
+
+/// Prints the registers saved by the last trap of the hart and the trap registers.
+fn dump_trap(uart: &mut UART) {
+    let frame = cpu::trap_frame();
+    writeln!(uart, "Trapped registers:").ok();
+    for (i, reg) in frame.regs.iter().enumerate() {
+        let end = if i % 4 == 3 { "\n" } else { "  " };
+        write!(uart, "{:>4}: 0x{:016x}{}", cpu::REG_NAMES[i], reg, end).ok();
+    }
+    let mode = if cfg!(feature = "sbi") { "s" } else { "m" };
+    writeln!(
+        uart,
+        "{m}cause: 0x{:x}, {m}tval: 0x{:x}, {m}status: 0x{:x}",
+        cpu::cause_read(),
+        cpu::tval_read(),
+        cpu::status_read(),
+        m = mode
+    )
+    .ok();
+    // The trap came from the kernel or from the user prog of the hart.
+    let epc = cpu::epc_read();
+    match unsafe { scheduler::force_cur() } {
+        Some((id, _)) if !cpu::trap_from_kernel() => {
+            writeln!(uart, "{}epc: {}", mode, symbols::user_addr(id, epc))
+        }
+        _ => writeln!(uart, "{}epc: {}", mode, symbols::kernel_addr(epc)),
+    }
+    .ok();
+}
+
+/// Prints the return addresses on the stack of the hart, following the frame pointers.
+/// The return address of a frame is stored right below its frame pointer, the frame pointer of the caller below it.
+fn backtrace(uart: &mut UART) {
+    writeln!(uart, "Backtrace:").ok();
+    let (memory_start, memory_end) = match platform::try_get() {
+        Some(platform) => (platform.memory_start, platform.memory_end),
+        None => return,
+    };
+    let mut fp = cpu::frame_pointer();
+    for i in 0..MAX_FRAMES {
+        // Every frame lies on a kernel or trap stack in memory, the callers above their callees.
+        if fp % 8 != 0 || fp < memory_start + 16 || fp > memory_end {
+            return;
+        }
+        let (ra, caller_fp) =
+            unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            return;
+        }
+        writeln!(uart, "  #{:<2} {}", i, symbols::kernel_addr(ra)).ok();
+        if caller_fp <= fp {
+            return;
+        }
+        fp = caller_fp;
+    }
 }
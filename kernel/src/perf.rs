@@ -0,0 +1,376 @@
+// Hardware performance counters and a sampling profiler.
+// mcycle and minstret count the cycles and the retired instructions of a hart. The kernel charges what they count
+// while a user prog runs in U-mode to it, see [enter_user] and [leave_user]. The counteren registers let the user
+// progs read cycle, time and instret themselves.
+// mhpmcounter3 - 31 count the events selected with `hpmevent<n>=<event>` in the boot arguments, which events exist
+// depends on the cpu. In S-mode the firmware owns the event selectors, the counters can only be read.
+// With `profile` in the boot arguments, the timer ticks [SAMPLES_PER_SLICE] times per time slice and each tick
+// samples the pc it interrupted, which gives a flat profile of the functions. The kernel runs with the interrupts off,
+// so it is only sampled where it notices a tick: in the idle loop and at the end of a trap that outlasted one.
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::cpu::{self, MAX_HARTS};
+use crate::scheduler::Prog;
+use crate::sync::SpinLock;
+use crate::{platform, symbols};
+use riscv_utils::*;
+
+/// Ticks of the timer per time slice, while the profiler is on.
+const SAMPLES_PER_SLICE: usize = 100;
+const FIRST_HPM: usize = 3;
+const LAST_HPM: usize = 31;
+/// The cy, tm and ir bits of mcounteren and scounteren.
+const COUNTEREN: usize = 0b111;
+/// The extension bit of S-mode in misa.
+#[cfg(not(feature = "sbi"))]
+const MISA_S: usize = 1 << (b'S' - b'A');
+#[cfg(not(feature = "sbi"))]
+const MHPMCOUNTER: usize = 0xb00;
+#[cfg(not(feature = "sbi"))]
+const MHPMEVENT: usize = 0x320;
+#[cfg(feature = "sbi")]
+const HPMCOUNTER: usize = 0xc00;
+
+/// Functions the profile keeps apart, the samples of the others are only counted.
+const MAX_BUCKETS: usize = 256;
+/// Bytes of code a bucket covers, if the symbol table has no function for a pc.
+const BUCKET_SIZE: usize = 64;
+/// Functions printed by [print_profile].
+const PRINTED_BUCKETS: usize = 20;
+
+/// Calls `$f` with the csr `$base + $counter` as parameter. The number of a csr is part of the instruction,
+/// so there is a call for each counter.
+macro_rules! with_hpm {
+    ($counter:expr, $base:expr, $f:ident($($arg:expr),*)) => {
+        match $counter {
+            3 => $f::<{ $base + 3 }>($($arg),*),
+            4 => $f::<{ $base + 4 }>($($arg),*),
+            5 => $f::<{ $base + 5 }>($($arg),*),
+            6 => $f::<{ $base + 6 }>($($arg),*),
+            7 => $f::<{ $base + 7 }>($($arg),*),
+            8 => $f::<{ $base + 8 }>($($arg),*),
+            9 => $f::<{ $base + 9 }>($($arg),*),
+            10 => $f::<{ $base + 10 }>($($arg),*),
+            11 => $f::<{ $base + 11 }>($($arg),*),
+            12 => $f::<{ $base + 12 }>($($arg),*),
+            13 => $f::<{ $base + 13 }>($($arg),*),
+            14 => $f::<{ $base + 14 }>($($arg),*),
+            15 => $f::<{ $base + 15 }>($($arg),*),
+            16 => $f::<{ $base + 16 }>($($arg),*),
+            17 => $f::<{ $base + 17 }>($($arg),*),
+            18 => $f::<{ $base + 18 }>($($arg),*),
+            19 => $f::<{ $base + 19 }>($($arg),*),
+            20 => $f::<{ $base + 20 }>($($arg),*),
+            21 => $f::<{ $base + 21 }>($($arg),*),
+            22 => $f::<{ $base + 22 }>($($arg),*),
+            23 => $f::<{ $base + 23 }>($($arg),*),
+            24 => $f::<{ $base + 24 }>($($arg),*),
+            25 => $f::<{ $base + 25 }>($($arg),*),
+            26 => $f::<{ $base + 26 }>($($arg),*),
+            27 => $f::<{ $base + 27 }>($($arg),*),
+            28 => $f::<{ $base + 28 }>($($arg),*),
+            29 => $f::<{ $base + 29 }>($($arg),*),
+            30 => $f::<{ $base + 30 }>($($arg),*),
+            31 => $f::<{ $base + 31 }>($($arg),*),
+            counter => panic!("No hpm counter: {}", counter),
+        }
+    };
+}
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+const NO: AtomicBool = AtomicBool::new(false);
+/// Ticks of each hart in the current time slice.
+static TICKS: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+/// The kernel was sampled for the tick already, the timer interrupt that follows is not sampled again.
+static LATE: [AtomicBool; MAX_HARTS] = [NO; MAX_HARTS];
+/// The counters of each hart, when it returned to its user prog.
+static START_CYCLES: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+static START_INSTRET: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+/// The event of each hpm counter, 0 for none.
+static EVENTS: [AtomicUsize; LAST_HPM + 1] = [ZERO; LAST_HPM + 1];
+
+static PROFILE: SpinLock<Profile> = SpinLock::new(Profile {
+    buckets: [Bucket::EMPTY; MAX_BUCKETS],
+    len: 0,
+    other: 0,
+    total: 0,
+});
+
+/// Reads `profile` and the `hpmevent<n>=<event>` selectors from the boot arguments.
+/// The events are written by [init_hart].
+pub fn init() {
+    for arg in platform::get().bootargs.split_ascii_whitespace() {
+        if arg == "profile" {
+            PROFILING.store(true, Ordering::Relaxed);
+            crate::info!("Profiling {} times per time slice", SAMPLES_PER_SLICE);
+        }
+        let (counter, event) = match arg
+            .strip_prefix("hpmevent")
+            .and_then(|arg| arg.split_once('='))
+        {
+            Some(selector) => selector,
+            None => continue,
+        };
+        let event = match event.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => event.parse(),
+        };
+        match (counter.parse::<usize>(), event) {
+            #[cfg(not(feature = "sbi"))]
+            (Ok(counter), Ok(event)) if (FIRST_HPM..=LAST_HPM).contains(&counter) => {
+                EVENTS[counter].store(event, Ordering::Relaxed)
+            }
+            #[cfg(feature = "sbi")]
+            (Ok(_), Ok(_)) => {
+                crate::warn!("The firmware selects the hpm events in S-mode: {}", arg)
+            }
+            _ => crate::warn!("Invalid hpm event: {}", arg),
+        }
+    }
+}
+
+/// Lets the user progs read the counters and selects the events of the hpm counters on the current hart.
+pub unsafe fn init_hart() {
+    let counteren = COUNTEREN;
+    #[cfg(not(feature = "sbi"))]
+    {
+        write_machine_reg!(counteren => "mcounteren");
+        // If the cpu has S-mode, U-mode needs the bits of scounteren as well. Without it the register doesn't exist.
+        let misa: usize;
+        read_machine_reg!("misa" => misa);
+        if misa & MISA_S != 0 {
+            write_machine_reg!(counteren => "scounteren");
+        }
+    }
+    #[cfg(feature = "sbi")]
+    write_machine_reg!(counteren => "scounteren");
+    #[cfg(not(feature = "sbi"))]
+    for (counter, event) in EVENTS.iter().enumerate().skip(FIRST_HPM) {
+        let event = event.load(Ordering::Relaxed);
+        if event != 0 {
+            configure(counter, event);
+        }
+    }
+}
+
+/// Selects the `event` the hpm `counter` counts from now on and clears it.
+#[cfg(not(feature = "sbi"))]
+unsafe fn configure(counter: usize, event: usize) {
+    with_hpm!(counter, MHPMEVENT, write_csr(event));
+    with_hpm!(counter, MHPMCOUNTER, write_csr(0));
+}
+
+/// Returns the cycles of the current hart since it was reset.
+pub fn cycles() -> usize {
+    let cycles: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("mcycle" => cycles);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("cycle" => cycles);
+    }
+    cycles
+}
+
+/// Returns the instructions the current hart retired since it was reset.
+pub fn instret() -> usize {
+    let instret: usize;
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        read_machine_reg!("minstret" => instret);
+        #[cfg(feature = "sbi")]
+        read_machine_reg!("instret" => instret);
+    }
+    instret
+}
+
+/// Returns the hpm `counter` of the current hart, 3 to 31.
+pub fn hpm(counter: usize) -> usize {
+    unsafe {
+        #[cfg(not(feature = "sbi"))]
+        return with_hpm!(counter, MHPMCOUNTER, read_csr());
+        #[cfg(feature = "sbi")]
+        return with_hpm!(counter, HPMCOUNTER, read_csr());
+    }
+}
+
+/// Remembers the counters, when the hart returns to its user prog.
+pub fn enter_user() {
+    let hart = cpu::hart_id();
+    START_CYCLES[hart].store(cycles(), Ordering::Relaxed);
+    START_INSTRET[hart].store(instret(), Ordering::Relaxed);
+}
+
+/// Returns the cycles and the instructions since [enter_user], when the user prog trapped.
+/// It is called before the kernel lock is taken, the user prog is not charged for waiting on it.
+pub fn leave_user() -> (usize, usize) {
+    let hart = cpu::hart_id();
+    (
+        cycles().wrapping_sub(START_CYCLES[hart].load(Ordering::Relaxed)),
+        instret().wrapping_sub(START_INSTRET[hart].load(Ordering::Relaxed)),
+    )
+}
+
+pub fn is_profiling() -> bool {
+    PROFILING.load(Ordering::Relaxed)
+}
+
+/// Ticks of the timer per time slice.
+pub fn ticks_per_slice() -> u64 {
+    if is_profiling() {
+        SAMPLES_PER_SLICE as u64
+    } else {
+        1
+    }
+}
+
+/// Handles a tick of the timer, which interrupted `prog`, and samples its pc.
+/// Returns true, if its time slice is over.
+pub fn tick(prog: Prog) -> bool {
+    if !is_profiling() {
+        return true;
+    }
+    let hart = cpu::hart_id();
+    if !LATE[hart].swap(false, Ordering::Relaxed) {
+        let (image, pc) = (prog.id().image(), prog.pc());
+        PROFILE.lock().add(image, pc);
+    }
+    if TICKS[hart].fetch_add(1, Ordering::Relaxed) + 1 < SAMPLES_PER_SLICE {
+        return false;
+    }
+    TICKS[hart].store(0, Ordering::Relaxed);
+    true
+}
+
+/// Samples the kernel at the return address, where the caller noticed a tick.
+/// If the timer interrupt is still `pending`, e.g. at the end of a trap, it is not sampled again.
+#[inline(never)]
+pub fn sample_kernel(pending: bool) {
+    let pc = cpu::return_address();
+    if !is_profiling() {
+        return;
+    }
+    PROFILE.lock().add("kernel", pc);
+    LATE[cpu::hart_id()].store(pending, Ordering::Relaxed);
+}
+
+/// Writes the profile and the configured hpm counters of the current hart to the kernel log and clears the profile.
+/// Returns the number of samples.
+pub fn print_profile() -> usize {
+    let mut profile = PROFILE.lock();
+    let total = profile.total;
+    crate::info!("Profile of {} samples", total);
+    let len = profile.len;
+    let buckets = &mut profile.buckets[..len];
+    buckets.sort_unstable_by_key(|bucket| Reverse(bucket.samples));
+    for bucket in buckets.iter().take(PRINTED_BUCKETS) {
+        let permille = bucket.samples * 1000 / total.max(1);
+        match symbols::lookup_in(bucket.image, bucket.addr) {
+            Some(symbol) => crate::info!(
+                "{:>3}.{}% {:>7} {:<8} {}",
+                permille / 10,
+                permille % 10,
+                bucket.samples,
+                bucket.image,
+                symbol.name
+            ),
+            None => crate::info!(
+                "{:>3}.{}% {:>7} {:<8} 0x{:x}",
+                permille / 10,
+                permille % 10,
+                bucket.samples,
+                bucket.image,
+                bucket.addr
+            ),
+        }
+    }
+    let rest: usize = buckets
+        .iter()
+        .skip(PRINTED_BUCKETS)
+        .map(|b| b.samples)
+        .sum();
+    if rest + profile.other > 0 {
+        crate::info!("{} samples in other functions", rest + profile.other);
+    }
+    for (counter, event) in EVENTS.iter().enumerate().skip(FIRST_HPM) {
+        let event = event.load(Ordering::Relaxed);
+        if event != 0 {
+            crate::info!(
+                "hpmcounter{}: {} (event 0x{:x})",
+                counter,
+                hpm(counter),
+                event
+            );
+        }
+    }
+    profile.len = 0;
+    profile.other = 0;
+    profile.total = 0;
+    total
+}
+
+/// The samples of a function, or of [BUCKET_SIZE] bytes of code without a symbol.
+#[derive(Clone, Copy)]
+struct Bucket {
+    /// "kernel" or the image of a user prog, see [crate::user_prog::Id::image].
+    image: &'static str,
+    addr: usize,
+    samples: usize,
+}
+
+impl Bucket {
+    const EMPTY: Bucket = Bucket {
+        image: "",
+        addr: 0,
+        samples: 0,
+    };
+}
+
+/// The flat profile, `len` buckets are used.
+struct Profile {
+    buckets: [Bucket; MAX_BUCKETS],
+    len: usize,
+    /// Samples of the functions, which found no free bucket.
+    other: usize,
+    total: usize,
+}
+
+impl Profile {
+    fn add(&mut self, image: &'static str, pc: usize) {
+        self.total += 1;
+        let addr = match symbols::lookup_in(image, pc) {
+            Some(symbol) => pc - symbol.offset,
+            None => pc & !(BUCKET_SIZE - 1),
+        };
+        let len = self.len;
+        if let Some(bucket) = self.buckets[..len]
+            .iter_mut()
+            .find(|bucket| bucket.addr == addr && bucket.image == image)
+        {
+            bucket.samples += 1;
+        } else if len < MAX_BUCKETS {
+            self.buckets[len] = Bucket {
+                image,
+                addr,
+                samples: 1,
+            };
+            self.len += 1;
+        } else {
+            self.other += 1;
+        }
+    }
+}
+
+#[allow(dead_code)]
+unsafe fn read_csr<const CSR: usize>() -> usize {
+    let value: usize;
+    core::arch::asm!("csrr {}, {csr}", out(reg) value, csr = const CSR);
+    value
+}
+
+#[allow(dead_code)]
+unsafe fn write_csr<const CSR: usize>(value: usize) {
+    core::arch::asm!("csrw {csr}, {}", in(reg) value, csr = const CSR);
+}
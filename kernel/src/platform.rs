@@ -0,0 +1,245 @@
+// The machine the kernel runs on, discovered from the device tree at boot.
+// Everything the device tree does not describe keeps the value of the qemu virt machine.
+use crate::fdt::{self, Fdt, Token};
+use crate::sync::Once;
+use riscv_utils::PLIC_MEMORY_MAP_BASE;
+
+// Linker symbols exported by mem.S
+extern "C" {
+    static HEAP_START: usize;
+    static HEAP_SIZE: usize;
+}
+
+/// Maximal number of virtio-mmio slots, qemu virt has 8.
+pub const MAX_VIRTIO: usize = 8;
+/// Maximal depth of the device tree, qemu virt has 4 levels.
+const MAX_DEPTH: usize = 8;
+
+pub struct Platform {
+    pub memory_start: usize,
+    pub memory_end: usize,
+    pub harts: usize,
+    /// Frequency of the `time` counter in Hz.
+    pub timebase: u64,
+    pub uart: usize,
+    /// Input clock of the uart in Hz, the baud rate is divided from it.
+    pub uart_clock: u32,
+    /// A second uart, the gdb stub uses it instead of the console.
+    pub debug_uart: Option<usize>,
+    /// Uarts found in the device tree, the first one is the console.
+    uart_count: usize,
+    pub plic: usize,
+    pub clint: usize,
+    /// Base addresses of the virtio-mmio slots, the first `virtio_count` are valid.
+    virtio: [usize; MAX_VIRTIO],
+    virtio_count: usize,
+    /// Start and size of the device tree blob, if qemu passed one.
+    pub dtb: Option<(usize, usize)>,
+    /// Kernel command line from /chosen, e.g. set with `-append`.
+    pub bootargs: &'static str,
+}
+
+impl Platform {
+    /// Base addresses of the virtio-mmio slots.
+    pub fn virtio(&self) -> &[usize] {
+        &self.virtio[..self.virtio_count]
+    }
+}
+
+static PLATFORM: Once<Platform> = Once::new();
+
+/// Defaults of the qemu virt machine, the memory is taken from the linker script by [init].
+const QEMU_VIRT: Platform = Platform {
+    memory_start: 0x8000_0000,
+    memory_end: 0,
+    harts: 1,
+    timebase: 10_000_000,
+    uart: 0x1000_0000,
+    uart_clock: crate::hardware::uart::DEFAULT_CLOCK,
+    debug_uart: None,
+    uart_count: 0,
+    plic: PLIC_MEMORY_MAP_BASE,
+    clint: 0x0200_0000,
+    virtio: [0; MAX_VIRTIO],
+    virtio_count: 0,
+    dtb: None,
+    bootargs: "",
+};
+
+/// The properties of a node the kernel is interested in.
+#[derive(Clone, Copy)]
+struct Node {
+    name: &'static str,
+    compatible: &'static [u8],
+    reg: &'static [u8],
+    device_type: &'static [u8],
+    clock_frequency: &'static [u8],
+    /// Cells of the addresses and sizes in the reg properties of the children.
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl Node {
+    const fn new(name: &'static str) -> Self {
+        // Defaults of the specification
+        Node {
+            name,
+            compatible: &[],
+            reg: &[],
+            device_type: &[],
+            clock_frequency: &[],
+            address_cells: 2,
+            size_cells: 1,
+        }
+    }
+}
+
+/// Returns the machine description.
+pub fn get() -> &'static Platform {
+    try_get().expect("The platform is used before platform::init")
+}
+
+/// Returns the machine description, if [init] was called.
+pub fn try_get() -> Option<&'static Platform> {
+    PLATFORM.get()
+}
+
+/// Reads the device tree at `dtb`. Falls back to the qemu virt machine, if there is none.
+/// Has to be called before the memory and the devices are used.
+pub unsafe fn init(dtb: usize) {
+    PLATFORM.call_once(|| read(dtb));
+}
+
+unsafe fn read(dtb: usize) -> Platform {
+    let mut platform = QEMU_VIRT;
+    platform.memory_end = HEAP_START + HEAP_SIZE;
+    let fdt = match Fdt::from_addr(dtb) {
+        Some(fdt) => fdt,
+        None => return platform,
+    };
+    platform.dtb = Some((fdt.addr(), fdt.size()));
+    // The harts are counted, if the tree describes them.
+    let mut harts = 0;
+    let mut nodes = [Node::new(""); MAX_DEPTH];
+    let mut depth = 0;
+    for token in fdt.tokens() {
+        match token {
+            Token::BeginNode(name) => {
+                depth += 1;
+                assert!(depth < MAX_DEPTH, "The device tree is too deep");
+                nodes[depth] = Node::new(name);
+            }
+            Token::Prop(name, value) => {
+                let node = &mut nodes[depth];
+                match name {
+                    "compatible" => node.compatible = value,
+                    "reg" => node.reg = value,
+                    "device_type" => node.device_type = value,
+                    "bootargs" if node.name == "chosen" => {
+                        platform.bootargs = fdt::read_str(value);
+                    }
+                    "clock-frequency" => node.clock_frequency = value,
+                    "#address-cells" => {
+                        node.address_cells = fdt::read_cells(value, 1).map_or(2, |(v, _)| v)
+                    }
+                    "#size-cells" => {
+                        node.size_cells = fdt::read_cells(value, 1).map_or(1, |(v, _)| v)
+                    }
+                    // On /cpus or on every cpu
+                    // The time is divided by it, so an empty or zero value keeps the default.
+                    // Nothing can be logged yet, the log needs the platform.
+                    "timebase-frequency" => {
+                        if let Some((freq, _)) = fdt::read_cells(value, value.len().min(8) / 4) {
+                            if freq != 0 {
+                                platform.timebase = freq as u64;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Token::EndNode => {
+                if depth == 0 {
+                    break;
+                }
+                // The reg property is interpreted with the cells of the parent.
+                let parent = nodes[depth - 1];
+                if nodes[depth].device_type == b"cpu\0" {
+                    harts += 1;
+                }
+                discover(&mut platform, &nodes[depth], &parent);
+                depth -= 1;
+            }
+        }
+    }
+    if harts > 0 {
+        platform.harts = harts;
+    }
+    platform
+}
+
+/// Takes the address of a device or memory node the kernel knows.
+fn discover(platform: &mut Platform, node: &Node, parent: &Node) {
+    let (base, size) = match fdt::read_cells(node.reg, parent.address_cells)
+        .and_then(|(base, rest)| Some((base, fdt::read_cells(rest, parent.size_cells)?.0)))
+    {
+        Some(reg) => reg,
+        None => return,
+    };
+    let compatible = |name| fdt::is_compatible(node.compatible, name);
+    if node.device_type == b"memory\0" || node.name.starts_with("memory@") {
+        // Only the bank the kernel is linked into is used.
+        let heap_start = unsafe { HEAP_START };
+        if base <= heap_start && heap_start < base + size {
+            platform.memory_start = base;
+            platform.memory_end = base + size;
+        }
+    } else if compatible("ns16550a") {
+        if platform.uart_count == 0 {
+            platform.uart = base;
+            if let Some((clock, _)) = fdt::read_cells(node.clock_frequency, 1) {
+                platform.uart_clock = clock as u32;
+            }
+        } else if platform.debug_uart.is_none() {
+            platform.debug_uart = Some(base);
+        }
+        platform.uart_count += 1;
+    } else if compatible("riscv,plic0") || compatible("sifive,plic-1.0.0") {
+        platform.plic = base;
+    } else if compatible("riscv,clint0") || compatible("sifive,clint0") {
+        platform.clint = base;
+    } else if compatible("virtio,mmio") && platform.virtio_count < MAX_VIRTIO {
+        platform.virtio[platform.virtio_count] = base;
+        platform.virtio_count += 1;
+    }
+}
+
+/// Prints what was found.
+pub unsafe fn print() {
+    let platform = get();
+    match platform.dtb {
+        Some((addr, size)) => {
+            crate::info!("DTB:    0x{:x} -> 0x{:x}", addr, addr + size);
+        }
+        None => {
+            crate::info!("DTB:    none, using the defaults of qemu virt");
+        }
+    }
+    crate::info!(
+        "MEMORY: 0x{:x} -> 0x{:x}, {} hart(s), timebase {} Hz",
+        platform.memory_start,
+        platform.memory_end,
+        platform.harts,
+        platform.timebase
+    );
+    crate::info!(
+        "UART:   0x{:x}, PLIC: 0x{:x}, CLINT: 0x{:x}, {} virtio slot(s)",
+        platform.uart,
+        platform.plic,
+        platform.clint,
+        platform.virtio().len()
+    );
+    if let Some(debug_uart) = platform.debug_uart {
+        crate::info!("DEBUG UART: 0x{:x}", debug_uart);
+    }
+}
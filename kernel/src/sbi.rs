@@ -0,0 +1,93 @@
+// Calls into the SBI firmware, which runs in M-mode below the kernel.
+// See https://github.com/riscv-non-isa/riscv-sbi-doc
+
+// Extension ids
+const EXT_LEGACY_PUTCHAR: usize = 0x01;
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x5449_4d45;
+const EXT_IPI: usize = 0x0073_5049;
+const EXT_SRST: usize = 0x5352_5354;
+const EXT_DBCN: usize = 0x4442_434e;
+pub const EXT_HSM: usize = 0x0048_534d;
+
+const BASE_PROBE_EXTENSION: usize = 3;
+const DBCN_WRITE_BYTE: usize = 2;
+
+/// Reset types of the SRST extension.
+#[repr(usize)]
+#[derive(Clone, Copy)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+/// Reset reasons of the SRST extension.
+#[repr(usize)]
+#[derive(Clone, Copy)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+/// Calls the function `fid` of the extension `eid`. Returns the error code and the value.
+fn call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    (error, value)
+}
+
+/// Returns true if the firmware implements the extension `eid`.
+pub fn probe_extension(eid: usize) -> bool {
+    let (error, value) = call(EXT_BASE, BASE_PROBE_EXTENSION, eid, 0, 0);
+    error == 0 && value != 0
+}
+
+/// Checks that the firmware implements the extensions the kernel can't run without.
+pub fn init() {
+    assert!(
+        probe_extension(EXT_TIME),
+        "The SBI firmware has no timer extension"
+    );
+}
+
+/// Triggers the supervisor timer interrupt at `time` and clears the pending one.
+pub fn set_timer(time: u64) {
+    call(EXT_TIME, 0, time as usize, 0, 0);
+}
+
+/// Raises the supervisor software interrupt on the harts in `hart_mask`, relative to `hart_mask_base`.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) {
+    call(EXT_IPI, 0, hart_mask, hart_mask_base, 0);
+}
+
+/// Writes a byte to the console of the firmware.
+/// Falls back to the legacy extension, if the debug console extension is missing.
+pub fn console_putchar(byte: u8) {
+    let (error, _) = call(EXT_DBCN, DBCN_WRITE_BYTE, byte as usize, 0, 0);
+    if error != 0 {
+        call(EXT_LEGACY_PUTCHAR, 0, byte as usize, 0, 0);
+    }
+}
+
+/// Shuts down or reboots the machine. Only returns, if the firmware can't do it.
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) {
+    call(EXT_SRST, 0, reset_type as usize, reason as usize, 0);
+}
+
+/// Starts the stopped hart `hart_id` in S-mode at `start` with `opaque` in a1.
+/// Returns false, if the hart could not be started, e.g. because it is running already.
+pub fn hart_start(hart_id: usize, start: usize, opaque: usize) -> bool {
+    let (error, _) = call(EXT_HSM, 0, hart_id, start, opaque);
+    error == 0
+}
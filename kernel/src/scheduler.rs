@@ -1,59 +1,237 @@
 use crate::{
+    asm,
+    cpu::{self, TrapFrame, MAX_HARTS},
     hardware::{clint, pmp},
+    ipi::{self, Ipi},
+    perf, smp,
+    sync::SpinLock,
     user_prog,
+    vma::{self, AddressSpace},
 };
-use riscv_utils::*;
 
-static mut CUR_PROG_IDX: usize = 0;
+pub const MAX_PROGS: usize = 8;
 const NONE: Option<ProgData> = None;
-static mut PROGS: [Option<ProgData>; 2] = [NONE; 2];
+static SCHEDULER: SpinLock<Scheduler> = SpinLock::new(Scheduler {
+    progs: [NONE; MAX_PROGS],
+    cur: [None; MAX_HARTS],
+    next_pid: 1,
+});
 
-pub unsafe fn boot_prog(prog: Prog) {
-    let prog_data = prog.get();
-    prog_data.state = State::Rdy;
+struct Scheduler {
+    progs: [Option<ProgData>; MAX_PROGS],
+    /// The index of the user prog each hart runs, None while the hart is idle.
+    cur: [Option<usize>; MAX_HARTS],
+    /// The pid of the next created user prog.
+    next_pid: usize,
+}
+
+impl Scheduler {
+    fn get(&mut self, prog: Prog) -> &mut ProgData {
+        if let Some(data) = &mut self.progs[prog.idx] {
+            if data.info.id == prog.id {
+                return data;
+            }
+            panic!(
+                "Tried to access a user prog: {:?}, at: {}, but a different user prog was found: {:?}",
+                prog.id, prog.idx, data.info.id
+            );
+        }
+        panic!(
+            "Tried to access a not existing user prog: {:?}, at: {}",
+            prog.id, prog.idx
+        );
+    }
+    /// Returns the current user prog of the hart.
+    fn cur(&self) -> Option<Prog> {
+        let idx = self.cur[cpu::hart_id()]?;
+        let data = self.progs[idx].as_ref()?;
+        Some(Prog {
+            idx,
+            id: data.info.id,
+        })
+    }
+    fn free_idx(&self) -> Option<usize> {
+        self.progs.iter().position(Option::is_none)
+    }
+    fn new_pid(&mut self) -> usize {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        pid
+    }
+    /// Wakes up an idle hart for a user prog that became ready.
+    fn wake_idle_hart(&self) {
+        if let Some(hart) = smp::others().find(|&hart| self.cur[hart].is_none()) {
+            ipi::send(hart, Ipi::Reschedule);
+        }
+    }
+}
+
+pub unsafe fn boot_prog(prog: Prog) -> ! {
+    SCHEDULER.lock().get(prog).state = State::Rdy;
     switch(prog);
-    riscv_utils::write_machine_reg!(prog_data.info.boot_mepc => "mepc");
-    crate::println!("\n\n## Starting {:?} ##", prog_data.info.id);
+    crate::info!("Starting {:?} on hart {}", prog.id, cpu::hart_id());
+    resume();
+}
+/// Returns to the current user prog of the hart and releases the kernel lock.
+unsafe fn resume() -> ! {
+    let frame = cpu::trap_frame();
+    restore_cur_prog(frame);
     clint::set_time_cmp();
-    core::arch::asm!("mret");
+    smp::unlock();
+    asm::exception_return(frame as *mut TrapFrame as usize);
+}
+/// Runs on a hart without a user prog, until one is ready. The kernel lock has to be held.
+pub unsafe fn idle() -> ! {
+    SCHEDULER.lock().cur[cpu::hart_id()] = None;
+    loop {
+        if let Some(next) = next() {
+            switch(next);
+            resume();
+        }
+        // Let the other harts into the kernel, while this one waits for its timer, the uart or another hart.
+        // The interrupts stay off, but wake the hart up.
+        clint::set_time_cmp();
+        smp::unlock();
+        cpu::wait_for_interrupt();
+        ipi::halt_if_requested();
+        smp::lock();
+        if cpu::interrupt_pending(cpu::TIMER_INTERRUPT) {
+            perf::sample_kernel(false);
+        }
+        // A reschedule needs nothing else, the loop looks for a user prog anyway.
+        if cpu::interrupt_pending(cpu::SOFTWARE_INTERRUPT) {
+            ipi::handle();
+        }
+        if cpu::interrupt_pending(cpu::EXTERNAL_INTERRUPT) {
+            crate::hardware::plic::dispatch();
+        }
+    }
 }
 pub unsafe fn end_prog(prog: Prog) {
-    prog.get();
-    PROGS[prog.idx] = None;
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.get(prog).space.free();
+    scheduler.progs[prog.idx] = None;
+    let cur = &mut scheduler.cur[cpu::hart_id()];
+    if *cur == Some(prog.idx) {
+        *cur = None;
+    }
 }
 pub unsafe fn init_prog(prog_info: user_prog::Info) -> Prog {
-    let idx = get_free_idx();
-    PROGS[idx] = Some(ProgData::new(prog_info));
+    let mut scheduler = SCHEDULER.lock();
+    let idx = scheduler
+        .free_idx()
+        .expect("No free index for user prog available");
+    let pid = scheduler.new_pid();
+    scheduler.progs[idx] = Some(ProgData::new(prog_info, idx, pid));
+    scheduler.wake_idle_hart();
     Prog {
         idx,
         id: prog_info.id,
     }
 }
-/// Returns the current user prog.
+/// Creates a user prog from the image of `prog_info` as a child of `parent`. Its syscalls are logged, if `trace` is set.
+/// Returns None if there is no free index.
+pub unsafe fn spawn_prog(prog_info: user_prog::Info, parent: Prog, trace: bool) -> Option<Prog> {
+    let mut scheduler = SCHEDULER.lock();
+    let idx = scheduler.free_idx()?;
+    let parent_pid = scheduler.get(parent).pid;
+    let pid = scheduler.new_pid();
+    let mut data = ProgData::new(prog_info, idx, pid);
+    data.parent = Some(parent_pid);
+    data.trace = trace;
+    scheduler.progs[idx] = Some(data);
+    scheduler.wake_idle_hart();
+    Some(Prog {
+        idx,
+        id: prog_info.id,
+    })
+}
+/// Creates a copy of the user prog, which continues after the fork syscall with a return value of 0.
+/// The copy is traced, if the user prog is.
+/// Returns None if there is no free index or memory left.
+pub unsafe fn fork_prog(parent: Prog) -> Option<Prog> {
+    let mut scheduler = SCHEDULER.lock();
+    let idx = scheduler.free_idx()?;
+    let parent_data = scheduler.get(parent);
+    // asid 0 is used by the kernel.
+    let space = parent_data.space.fork(idx + 1)?;
+    let mut frame = parent_data.frame;
+    frame.satp = space.satp();
+    frame.set_ret(0);
+    let (info, parent_pid, mepc, pmp, trace) = (
+        parent_data.info,
+        parent_data.pid,
+        parent_data.mepc,
+        parent_data.pmp,
+        parent_data.trace,
+    );
+    let pid = scheduler.new_pid();
+    scheduler.progs[idx] = Some(ProgData {
+        info,
+        pid,
+        parent: Some(parent_pid),
+        mepc,
+        pmp,
+        frame,
+        space,
+        state: State::Rdy,
+        trace,
+        cycles: 0,
+        instret: 0,
+    });
+    scheduler.wake_idle_hart();
+    Some(Prog { idx, id: info.id })
+}
+/// Returns the current user prog of the hart.
 pub fn cur() -> Prog {
-    unsafe {
-        if let Some(cur) = &mut PROGS[CUR_PROG_IDX] {
-            return Prog {
-                idx: CUR_PROG_IDX,
-                id: cur.info.id,
-            };
-        }
-        panic!("Tried to access current user prog. But none was running");
+    match SCHEDULER.lock().cur() {
+        Some(cur) => cur,
+        None => panic!("Tried to access current user prog. But none was running"),
     }
 }
+/// Returns true if the hart runs a user prog, which has not ended.
+pub fn has_cur() -> bool {
+    SCHEDULER.lock().cur().is_some()
+}
+/// Returns the user prog with `pid`, if it exists.
+pub fn find(pid: usize) -> Option<Prog> {
+    let scheduler = SCHEDULER.lock();
+    scheduler.progs.iter().enumerate().find_map(|(idx, prog)| {
+        let prog = prog.as_ref()?;
+        (prog.pid == pid).then_some(Prog {
+            idx,
+            id: prog.info.id,
+        })
+    })
+}
+/// Returns the pids of the user progs.
+pub fn pids() -> [Option<usize>; MAX_PROGS] {
+    SCHEDULER
+        .lock()
+        .progs
+        .each_ref()
+        .map(|prog| Some(prog.as_ref()?.pid))
+}
+/// Returns the id and the pid of the user prog the hart runs, without taking the lock.
+/// Only for the panic handler, the panicking code may hold it.
+pub unsafe fn force_cur() -> Option<(user_prog::Id, usize)> {
+    let scheduler = SCHEDULER.force_get();
+    let prog = scheduler.cur()?;
+    Some((prog.id, scheduler.progs[prog.idx].as_ref()?.pid))
+}
 /// Returns the next rdy or starting user prog after round robin.
+/// User progs running on a hart are skipped.
 pub fn next() -> Option<Prog> {
-    unsafe {
-        let start = CUR_PROG_IDX + 1;
-        for i in 0..PROGS.len() {
-            let idx = (start + i) % PROGS.len();
-            if let Some(next) = &mut PROGS[idx] {
-                if next.state == State::Rdy || next.state == State::Starting {
-                    return Some(Prog {
-                        idx,
-                        id: next.info.id,
-                    });
-                }
+    let scheduler = SCHEDULER.lock();
+    let start = scheduler.cur[cpu::hart_id()].map_or(0, |idx| idx + 1);
+    for i in 0..MAX_PROGS {
+        let idx = (start + i) % MAX_PROGS;
+        if let Some(next) = &scheduler.progs[idx] {
+            if next.state == State::Rdy || next.state == State::Starting {
+                return Some(Prog {
+                    idx,
+                    id: next.info.id,
+                });
             }
         }
     }
@@ -61,63 +239,86 @@ pub fn next() -> Option<Prog> {
 }
 /// Switches the program.
 pub fn switch(prog: Prog) {
-    unsafe {
-        let prog_data = prog.get();
-        match prog_data.state {
-            State::Rdy => {
-                CUR_PROG_IDX = prog.idx;
-                pmp::switch_prog_pmp(prog_data.info.pmp_idx);
+    let mut guard = SCHEDULER.lock();
+    let scheduler = &mut *guard;
+    let state = scheduler.get(prog).state;
+    match state {
+        State::Rdy => {
+            let cur = &mut scheduler.cur[cpu::hart_id()];
+            // The previous user prog of the hart can be picked up by every hart again.
+            let mut requeued = false;
+            if let Some(prev) = cur.and_then(|idx| scheduler.progs[idx].as_mut()) {
+                if prev.state == State::Running {
+                    prev.state = State::Rdy;
+                    requeued = true;
+                }
             }
-            State::Starting => {
-                boot_prog(prog);
+            *cur = Some(prog.idx);
+            if requeued {
+                scheduler.wake_idle_hart();
             }
-            State::_Blocked(_) => {
-                panic!(
-                    "Tried to switch to user prog: {:?}, with state: {:?}",
-                    prog_data.info.id, prog_data.state
-                )
+            let prog_data = scheduler.get(prog);
+            prog_data.state = State::Running;
+            // Another hart may have changed the address space, while this one still caches its old translations.
+            ipi::flush_pending();
+            // The firmware owns the pmp in S-mode.
+            #[cfg(not(feature = "sbi"))]
+            unsafe {
+                prog_data.pmp.write();
             }
         }
-    }
-}
-/// Safes the user prog.
-pub fn save_cur_prog(mepc: usize, sp: usize) {
-    unsafe {
-        if mepc < 0x80100000usize {
-            let mcause: usize;
-            read_machine_reg!("mcause" => mcause);
-
-            panic!("Interrupt in exception, mepc: {}, mcause: {}", mepc, mcause);
+        State::Starting => {
+            // Never returns, so the scheduler has to be unlocked first.
+            drop(guard);
+            unsafe { boot_prog(prog) };
         }
-        let prog = cur().get();
-        prog.mepc = mepc;
-        cur().increment_mepc();
-        prog.sp = sp;
-    }
-}
-/// Returns the stack pointer to restore it.
-pub fn restore_cur_prog() -> usize {
-    unsafe {
-        let prog = cur().get();
-        if prog.state == State::Rdy {
-            write_machine_reg!(prog.mepc => "mepc");
-            return prog.sp;
+        State::Running | State::_Blocked(_) => {
+            panic!(
+                "Tried to switch to user prog: {:?}, with state: {:?}",
+                prog.id, state
+            )
         }
+    }
+}
+/// Safes the user prog from the trap frame and charges it the cycles and instructions it ran, see [perf::leave_user].
+pub fn save_cur_prog(mepc: usize, frame: &TrapFrame, (cycles, instret): (usize, usize)) {
+    if cpu::trap_from_kernel() {
         panic!(
-            "Tried to restore user prog: {:?}, with state: {:?}",
-            prog.info.id, prog.state
+            "Interrupt in exception, mepc: {}, mcause: {}",
+            mepc,
+            cpu::cause_read()
         );
     }
+    let cur = cur();
+    let mut scheduler = SCHEDULER.lock();
+    let prog = scheduler.get(cur);
+    prog.mepc = mepc;
+    prog.frame.regs = frame.regs;
+    prog.cycles += cycles;
+    prog.instret += instret;
 }
-fn get_free_idx() -> usize {
-    unsafe {
-        for idx in 0..PROGS.len() {
-            if PROGS[idx].is_none() {
-                return idx;
-            }
+/// Writes the registers of the current user prog into the trap frame and sets mepc to resume it.
+pub fn restore_cur_prog(frame: &mut TrapFrame) {
+    let cur = cur();
+    let mut scheduler = SCHEDULER.lock();
+    let prog = scheduler.get(cur);
+    if prog.state == State::Running {
+        cpu::epc_write(prog.mepc);
+        frame.regs = prog.frame.regs;
+        frame.satp = prog.frame.satp;
+        cpu::satp_write(frame.satp);
+        // The firmware owns the pmp in S-mode.
+        #[cfg(not(feature = "sbi"))]
+        unsafe {
+            pmp::guard_kernel();
         }
+        perf::enter_user();
+        return;
     }
-    panic!("No free index for user prog available");
+    panic!(
+        "Tried to restore user prog: {:?}, with state: {:?}",
+        prog.info.id, prog.state
+    );
 }
 #[derive(PartialEq, Clone, Copy)]
 pub struct Prog {
@@ -125,64 +326,131 @@ pub struct Prog {
     id: user_prog::Id,
 }
 impl Prog {
-    unsafe fn get(&self) -> &'static mut ProgData {
-        if let Some(cur) = &mut PROGS[self.idx] {
-            if cur.info.id == self.id {
-                return cur;
-            }
-            panic!(
-                "Tried to access a user prog: {:?}, at: {}, but a different user prog was found: {:?}",
-                self.id, self.idx, cur.info.id
-            );
-        }
-        panic!(
-            "Tried to access a not existing user prog: {:?}, at: {}",
-            self.id, self.idx
-        );
+    /// Runs `f` on the data of the user prog, while the scheduler is locked.
+    fn with<R>(&self, f: impl FnOnce(&mut ProgData) -> R) -> R {
+        f(SCHEDULER.lock().get(*self))
     }
     pub fn _set_rdy(&self) {
-        unsafe {
-            self.get().state = State::Rdy;
-        }
+        self.with(|prog| prog.state = State::Rdy)
     }
     /// If blocked, returns the reason. Otherwise None.
     pub fn _is_blocked(&self, reason: Reason) -> bool {
-        unsafe { self.get().state == State::_Blocked(reason) }
+        self.with(|prog| prog.state == State::_Blocked(reason))
     }
     pub fn _set_blocked(&self, reason: Reason) {
-        unsafe {
-            self.get().state = State::_Blocked(reason);
-        }
+        self.with(|prog| prog.state = State::_Blocked(reason))
     }
     pub fn increment_mepc(&self) {
-        unsafe {
-            self.get().mepc += 4;
-        }
+        self.with(|prog| prog.mepc += 4)
+    }
+    /// Returns the address the user prog continues at.
+    pub fn pc(&self) -> usize {
+        self.with(|prog| prog.mepc)
+    }
+    pub fn set_pc(&self, pc: usize) {
+        self.with(|prog| prog.mepc = pc)
     }
     pub fn id(&self) -> user_prog::Id {
-        unsafe { self.get().info.id }
+        self.with(|prog| prog.info.id)
     }
     pub fn prog_info(&self) -> user_prog::Info {
-        unsafe { self.get().info }
+        self.with(|prog| prog.info)
+    }
+    pub fn pid(&self) -> usize {
+        self.with(|prog| prog.pid)
+    }
+    /// Returns true if the user prog was created by a fork or spawned by another one.
+    pub fn is_forked(&self) -> bool {
+        self.with(|prog| prog.parent.is_some())
+    }
+    /// Returns the pid of the user prog this one was forked from or spawned by.
+    pub fn parent(&self) -> Option<usize> {
+        self.with(|prog| prog.parent)
+    }
+    /// Returns true if the syscalls of the user prog are logged.
+    pub fn is_traced(&self) -> bool {
+        self.with(|prog| prog.trace)
+    }
+    pub fn set_traced(&self, trace: bool) {
+        self.with(|prog| prog.trace = trace)
+    }
+    /// Returns the cycles and the instructions the user prog ran in U-mode.
+    pub fn counters(&self) -> (usize, usize) {
+        self.with(|prog| (prog.cycles, prog.instret))
+    }
+    /// Returns a copy of the saved registers of the user prog.
+    pub fn regs(&self) -> TrapFrame {
+        self.with(|prog| prog.frame)
+    }
+    /// Overwrites the saved registers, e.g. for a debugger. x0 stays 0.
+    pub fn set_regs(&self, regs: [usize; 32]) {
+        self.with(|prog| {
+            prog.frame.regs = regs;
+            prog.frame.regs[0] = 0;
+        })
+    }
+    /// Sets the return value of the syscall the user prog made.
+    pub fn set_ret(&self, ret: usize) {
+        self.with(|prog| prog.frame.set_ret(ret))
+    }
+    /// Handles a page fault at `addr`. Returns false if the user prog accessed invalid memory.
+    pub fn handle_page_fault(&self, addr: usize, access: vma::Access) -> bool {
+        self.with(|prog| prog.space.handle_fault(addr, access))
+    }
+    /// Translates a virtual address of the user prog to a physical address.
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        self.with(|prog| prog.space.translate(vaddr))
+    }
+    /// Translates a virtual address the user prog may read, see [AddressSpace::translate_read].
+    pub fn translate_read(&self, vaddr: usize) -> Option<usize> {
+        self.with(|prog| prog.space.translate_read(vaddr))
     }
-    pub fn _sp(&self) -> usize {
-        unsafe { self.get().sp }
+    /// Runs `f` on the address space of the user prog.
+    pub fn with_space<R>(&self, f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+        self.with(|prog| f(&mut prog.space))
     }
 }
-#[derive(PartialEq)]
 struct ProgData {
     info: user_prog::Info,
+    pid: usize,
+    /// The pid of the user prog this one was forked from or spawned by.
+    parent: Option<usize>,
     mepc: usize,
-    sp: usize,
+    /// The pmp entries that are written when the user prog is switched to.
+    pmp: pmp::Pmp,
+    frame: TrapFrame,
+    space: AddressSpace,
     state: State,
+    /// The syscalls are logged, see [crate::system_calls::syscall].
+    trace: bool,
+    /// Cycles and instructions the user prog ran in U-mode, see [perf::leave_user].
+    cycles: usize,
+    instret: usize,
 }
 impl ProgData {
-    fn new(prog_info: user_prog::Info) -> Self {
+    fn new(prog_info: user_prog::Info, idx: usize, pid: usize) -> Self {
+        let header = prog_info.header();
+        // asid 0 is used by the kernel.
+        let space = AddressSpace::new(idx + 1, prog_info.load_addr, &header);
+        let mut frame = TrapFrame::new();
+        frame.regs[2] = vma::USER_STACK_TOP;
+        frame.satp = space.satp();
         ProgData {
             info: prog_info,
-            sp: 0,
-            mepc: 0,
+            pid,
+            parent: None,
+            frame,
+            space,
+            mepc: header.entry,
+            pmp: pmp::prog_pmp(
+                prog_info.load_addr,
+                prog_info.load_addr + header.offset(header.text_end),
+                prog_info.load_addr + header.offset(header.image_end),
+            ),
             state: State::Starting,
+            trace: false,
+            cycles: 0,
+            instret: 0,
         }
     }
 }
@@ -190,6 +458,8 @@ impl ProgData {
 #[derive(Clone, Copy, PartialEq, Debug)]
 enum State {
     Rdy,
+    /// Running on a hart, see [Scheduler::cur].
+    Running,
     _Blocked(Reason),
     Starting,
 }
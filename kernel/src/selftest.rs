@@ -0,0 +1,115 @@
+// Boot-time self-test of the memory code. The unit tests on the host can't reach the page allocator, so it checks it,
+// the refcounts of shared pages and the page table encoding on the real heap, before anything else uses it.
+// It runs in debug builds on the boot hart, a failing check panics with the reason.
+use crate::mmu::{self, EntryBits, Table};
+use crate::page::{self, PAGE_SIZE};
+
+/// Runs the checks, they leave the heap as they found it.
+pub fn run() {
+    let free = page::free_pages();
+    align();
+    alloc_and_free();
+    refcounts();
+    page_tables();
+    assert_eq!(page::free_pages(), free, "The self-test leaked pages");
+    crate::info!("Self-test of the page allocator and the page tables passed");
+}
+
+fn align() {
+    assert_eq!(page::align_val(0, 12), 0);
+    assert_eq!(page::align_val(1, 12), PAGE_SIZE);
+    assert_eq!(page::align_val(PAGE_SIZE, 12), PAGE_SIZE);
+    assert_eq!(page::align_val(PAGE_SIZE + 1, 12), 2 * PAGE_SIZE);
+    assert_eq!(page::align_val(0x1234, 4), 0x1240);
+}
+
+fn alloc_and_free() {
+    let free = page::free_pages();
+    let pages = page::alloc(3);
+    assert!(!pages.is_null(), "Out of memory in the self-test");
+    let addr = pages as usize;
+    assert_eq!(addr % PAGE_SIZE, 0, "Unaligned allocation at 0x{:x}", addr);
+    assert!(page::owns(addr) && page::owns(addr + 3 * PAGE_SIZE - 1));
+    assert_eq!(page::free_pages(), free - 3);
+    // A second allocation never overlaps the first one.
+    let other = page::alloc(1) as usize;
+    assert!(other != 0 && (other < addr || other >= addr + 3 * PAGE_SIZE));
+    page::dealloc(other as *mut u8);
+    unsafe { core::ptr::write_bytes(pages, 0xff, 3 * PAGE_SIZE) };
+    page::dealloc(pages);
+    assert_eq!(page::free_pages(), free);
+    // The first fitting pages are taken, so the freed ones come back and zalloc clears them.
+    let zeroed = page::zalloc(3);
+    assert_eq!(zeroed, pages, "Freed pages were not reused");
+    let words = unsafe { core::slice::from_raw_parts(zeroed as *const u64, 3 * PAGE_SIZE / 8) };
+    assert!(
+        words.iter().all(|&word| word == 0),
+        "zalloc returned dirty pages"
+    );
+    page::dealloc(zeroed);
+    // Addresses outside of the allocatable pages are not owned, e.g. the kernel code.
+    assert!(!page::owns(run as fn() as usize));
+}
+
+fn refcounts() {
+    let free = page::free_pages();
+    let addr = page::alloc(1) as usize;
+    assert!(addr != 0, "Out of memory in the self-test");
+    assert!(!page::is_shared(addr));
+    page::share(addr);
+    page::share(addr);
+    assert!(page::is_shared(addr));
+    // Each release drops one owner, the last one frees the page.
+    page::release(addr);
+    assert!(page::is_shared(addr));
+    page::release(addr);
+    assert!(!page::is_shared(addr));
+    assert_eq!(
+        page::free_pages(),
+        free - 1,
+        "A shared page was freed early"
+    );
+    page::release(addr);
+    assert_eq!(
+        page::free_pages(),
+        free,
+        "The last release did not free the page"
+    );
+}
+
+fn page_tables() {
+    let root = page::zalloc(1) as *mut Table;
+    assert!(!root.is_null(), "Out of memory in the self-test");
+    let root = unsafe { &mut *root };
+    let frame = page::zalloc(1) as usize;
+    assert!(frame != 0, "Out of memory in the self-test");
+
+    // A 4 KiB page with all three levels
+    let vaddr = 0x12_3456_7000;
+    mmu::map(root, vaddr, frame, EntryBits::UserReadWrite.val(), 0);
+    assert_eq!(mmu::virt_to_phys(root, vaddr + 0x123), Some(frame + 0x123));
+    let expected = EntryBits::Valid.val()
+        | EntryBits::UserReadWrite.val()
+        | EntryBits::Access.val()
+        | EntryBits::Dirty.val();
+    assert_eq!(mmu::entry_bits(root, vaddr), Some(expected));
+    assert_eq!(mmu::virt_to_phys(root, vaddr + PAGE_SIZE), None);
+
+    // A 2 MiB page keeps the offset within the page
+    let mega = 0x4000_0000;
+    mmu::map(root, mega, 0x8020_0000, EntryBits::ReadExecute.val(), 1);
+    assert_eq!(mmu::virt_to_phys(root, mega + 0x1_2345), Some(0x8021_2345));
+
+    // Only the 4 KiB page is visited, the walk skips the empty tables.
+    let mut visited = 0;
+    mmu::for_each_page(root, 0, usize::MAX, &mut |addr, entry| {
+        assert_eq!(addr, vaddr);
+        assert_eq!(entry.get_physical_address(), frame);
+        visited += 1;
+    });
+    assert_eq!(visited, 1);
+
+    mmu::unmap(root);
+    page::dealloc(root as *mut Table as *mut u8);
+    page::dealloc(frame as *mut u8);
+}
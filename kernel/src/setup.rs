@@ -1,14 +1,35 @@
 use crate::hardware::binary_struct::BinaryStruct;
+use crate::sync::Once;
 use crate::{asm, hardware};
 use riscv_utils::*;
 
-static mut SETUP: bool = false;
+static SETUP: Once<()> = Once::new();
 
+/// Sets up the devices and the trap handling of the boot hart. Only the first call does something.
 pub unsafe fn setup() {
-    if SETUP {
-        return;
-    }
-    SETUP = true;
+    SETUP.call_once(|| {
+        // init hardware interrupt. The handlers are registered before any hart sets up its plic context.
+        hardware::uart::init();
+        setup_hart();
+    });
+}
+
+/// Sets up the trap handling, the timer, the interrupts of the devices and the pmp of the current hart.
+pub unsafe fn setup_hart() {
+    set_trap_mode();
+    // init timer interrupt.
+    hardware::clint::init();
+    // The interrupts of the devices go to every hart, the one that claims one first handles it.
+    hardware::plic::init_hart();
+    // configure Physical Memory Protection to lock the kernel sections. In S-mode the firmware owns it.
+    #[cfg(not(feature = "sbi"))]
+    hardware::pmp::init();
+    crate::perf::init_hart();
+    enable_interrupts();
+}
+
+#[cfg(not(feature = "sbi"))]
+unsafe fn set_trap_mode() {
     // set M Previous Privilege mode to User so mret returns to user mode.
     let mstatus: usize;
     read_machine_reg!("mstatus" => mstatus);
@@ -16,26 +37,36 @@ pub unsafe fn setup() {
     mstatus.write_register_entry(MSTATUS_MPP_U.0);
     mstatus.write_register_entry(MSTATUS_MPP_U.1);
 
-    // enable machine-mode interrupts.
-    mstatus.write_register_entry(MSTATUS_MIE);
+    // enable machine-mode interrupts after mret. The kernel runs with them off,
+    // an idle hart is woken up by wfi without taking the trap.
+    mstatus.write_register_entry(MSTATUS_MPIE);
     let mstatus = mstatus.get();
     write_machine_reg!(mstatus => "mstatus");
 
-    // set the machine-mode trap handler.
+    // set the machine-mode trap handler. Paging is turned on by kmem::init.
     let trap_handler = asm::exception as usize;
-    // disable paging for now.
-    let paging = 0usize;
-    write_machine_reg!(
-        trap_handler => "mtvec",
-        paging => "satp"
-    );
-    // init timer interrupt.
-    hardware::clint::init();
-    // init hardware interrupt.
-    hardware::plic::init();
-    hardware::uart::init();
-    // configure Physical Memory Protection to give user mode access to all of physical memory.
-    hardware::pmp::init();
+    write_machine_reg!(trap_handler => "mtvec");
+}
+
+#[cfg(feature = "sbi")]
+unsafe fn set_trap_mode() {
+    crate::sbi::init();
+    // set S Previous Privilege mode to User so sret returns to user mode.
+    // Supervisor interrupts are always enabled in user mode, sstatus.SIE stays off in the kernel.
+    let sstatus: usize;
+    read_machine_reg!("sstatus" => sstatus);
+    let mut sstatus = BinaryStruct::from(sstatus);
+    sstatus.write_register_entry(SSTATUS_SPP_U);
+    let sstatus = sstatus.get();
+    write_machine_reg!(sstatus => "sstatus");
+
+    // set the supervisor-mode trap handler. Paging is turned on by kmem::init.
+    let trap_handler = asm::exception as usize;
+    write_machine_reg!(trap_handler => "stvec");
+}
+
+#[cfg(not(feature = "sbi"))]
+unsafe fn enable_interrupts() {
     // enable software interrupts (ecall) in M mode. enable timer interrupts.
     let mie: usize;
     read_machine_reg!("mie" => mie);
@@ -45,3 +76,15 @@ pub unsafe fn setup() {
     mie.write_register_entry(MIE_MEIE);
     write_machine_reg!(mie.get() => "mie");
 }
+
+#[cfg(feature = "sbi")]
+unsafe fn enable_interrupts() {
+    // enable software, timer and external interrupts in S mode.
+    let sie: usize;
+    read_machine_reg!("sie" => sie);
+    let mut sie = BinaryStruct::from(sie);
+    sie.write_register_entry(SIE_SSIE);
+    sie.write_register_entry(SIE_STIE);
+    sie.write_register_entry(SIE_SEIE);
+    write_machine_reg!(sie.get() => "sie");
+}
@@ -0,0 +1,80 @@
+// Support for more than one hart (qemu -smp N).
+// The boot hart sets up the kernel, the other harts wait until it releases them.
+// The kernel state is protected by one big lock. A hart holds it while it runs in the kernel,
+// it is released right before the hart returns to a user prog and while the hart is idle.
+use crate::sync::SpinLock;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static KERNEL_LOCK: SpinLock<()> = SpinLock::new(());
+/// Set by the boot hart, once the kernel is set up.
+static RELEASED: AtomicBool = AtomicBool::new(false);
+/// Set by the first hart entering the kernel under OpenSBI.
+static BOOTED: AtomicBool = AtomicBool::new(false);
+/// The harts running the kernel, one bit per hart.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Takes the big kernel lock, spins while another hart holds it.
+/// It is held across functions, so the guard is forgotten and [unlock] frees it.
+pub fn lock() {
+    loop {
+        if let Some(guard) = KERNEL_LOCK.try_lock() {
+            core::mem::forget(guard);
+            return;
+        }
+        // The holder may have panicked and asked the other harts to stop.
+        crate::ipi::halt_if_requested();
+        spin_loop();
+    }
+}
+
+/// Releases the big kernel lock.
+pub fn unlock() {
+    unsafe { KERNEL_LOCK.force_unlock() };
+}
+
+/// Marks the current hart as running the kernel, after its trap handling is set up.
+pub fn set_online() {
+    ONLINE.fetch_or(1 << crate::cpu::hart_id(), Ordering::AcqRel);
+}
+
+/// Returns the other harts running the kernel.
+pub fn others() -> impl Iterator<Item = usize> {
+    let online = ONLINE.load(Ordering::Acquire);
+    let hart_id = crate::cpu::hart_id();
+    (0..crate::cpu::MAX_HARTS).filter(move |&hart| hart != hart_id && online & 1 << hart != 0)
+}
+
+/// Returns true for the hart that sets up the kernel.
+/// Without a firmware qemu starts all harts at once, hart 0 sets up the kernel.
+/// OpenSBI only starts one hart, which may have any id. It starts the others in [release].
+pub fn is_boot_hart(hart_id: usize) -> bool {
+    if cfg!(all(feature = "sbi", not(feature = "builtin-sbi"))) {
+        !BOOTED.swap(true, Ordering::AcqRel)
+    } else {
+        hart_id == 0
+    }
+}
+
+/// Lets the other harts continue, after the boot hart has set up the kernel.
+/// Harts the firmware has not started yet are started at `_start` with the device tree at `dtb`.
+pub fn release(dtb: usize) {
+    RELEASED.store(true, Ordering::Release);
+    #[cfg(feature = "sbi")]
+    if crate::sbi::probe_extension(crate::sbi::EXT_HSM) {
+        let harts = crate::platform::get().harts.min(crate::cpu::MAX_HARTS);
+        let boot_hart = crate::cpu::hart_id();
+        for hart in (0..harts).filter(|&hart| hart != boot_hart) {
+            crate::sbi::hart_start(hart, crate::asm::_start as usize, dtb);
+        }
+    }
+    #[cfg(not(feature = "sbi"))]
+    let _ = dtb;
+}
+
+/// Parks a secondary hart until the boot hart calls [release].
+pub fn wait_for_release() {
+    while !RELEASED.load(Ordering::Acquire) {
+        spin_loop();
+    }
+}
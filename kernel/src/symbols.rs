@@ -0,0 +1,130 @@
+// Symbol table of the kernel and the user progs, so fault reports and backtraces can name the functions.
+// The kernel reserves the space in its image, `kernel/symbols.py` fills it in after linking.
+// See the script for the layout.
+use core::fmt;
+
+use crate::user_prog;
+
+/// Bytes reserved for the table. The kernel and its stack have to fit below the user prog windows.
+const TABLE_SIZE: usize = 32 * 1024;
+const MAGIC: &[u8; 8] = b"symbols\0";
+const HEADER_SIZE: usize = 24;
+const IMAGE_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 16;
+
+/// Written by `kernel/symbols.py`, found by its name.
+#[no_mangle]
+#[used]
+static KERNEL_SYMBOLS: [u8; TABLE_SIZE] = empty_table();
+
+/// A table without symbols. Not all zeros, so it stays in the image instead of the bss.
+const fn empty_table() -> [u8; TABLE_SIZE] {
+    let mut table = [0; TABLE_SIZE];
+    let magic = b"nosyms\0\0";
+    let mut i = 0;
+    while i < magic.len() {
+        table[i] = magic[i];
+        i += 1;
+    }
+    table
+}
+
+/// The function an address lies in.
+pub struct Symbol {
+    pub name: &'static str,
+    /// Offset of the address from the start of the function.
+    pub offset: usize,
+}
+
+/// Returns the function `addr` lies in, in the `image` "kernel" or the name of a user prog like "user_1".
+pub fn lookup_in(image: &str, addr: usize) -> Option<Symbol> {
+    let table = table()?;
+    let images = read_u32(table, 8)?;
+    let pos = (0..images)
+        .map(|i| HEADER_SIZE + i * IMAGE_SIZE)
+        .find(|&pos| read_u32(table, pos).and_then(|name| string(table, name)) == Some(image))?;
+    let (first, count) = (read_u32(table, pos + 4)?, read_u32(table, pos + 8)?);
+    let entries = HEADER_SIZE + images * IMAGE_SIZE + first * ENTRY_SIZE;
+    let entry = |i: usize| {
+        let pos = entries + i * ENTRY_SIZE;
+        Some((
+            read_u64(table, pos)?,
+            read_u32(table, pos + 8)?,
+            read_u32(table, pos + 12)?,
+        ))
+    };
+    // The entries are sorted, the last one starting at or below `addr` may contain it.
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry(mid)?.0 <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let (start, size, name) = entry(low.checked_sub(1)?)?;
+    if addr >= start + size {
+        return None;
+    }
+    Some(Symbol {
+        name: string(table, name)?,
+        offset: addr - start,
+    })
+}
+
+/// Formats an address of the kernel with its function, e.g. `0x80001234 <scheduler::switch+0x24>`.
+pub fn kernel_addr(addr: usize) -> Addr {
+    Addr {
+        addr,
+        image: "kernel",
+    }
+}
+
+/// Formats an address of the user prog `id` with its function.
+pub fn user_addr(id: user_prog::Id, addr: usize) -> Addr {
+    Addr {
+        addr,
+        image: id.image(),
+    }
+}
+
+pub struct Addr {
+    addr: usize,
+    image: &'static str,
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:x}", self.addr)?;
+        match lookup_in(self.image, self.addr) {
+            Some(symbol) => write!(f, " <{}+0x{:x}>", symbol.name, symbol.offset),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the table, if the script filled it in.
+fn table() -> Option<&'static [u8]> {
+    // The compiler must not use the empty table it sees here, the script changes it after linking.
+    let table: &'static [u8; TABLE_SIZE] = core::hint::black_box(&KERNEL_SYMBOLS);
+    (&table[..MAGIC.len()] == MAGIC).then_some(table)
+}
+
+fn read_u32(table: &[u8], pos: usize) -> Option<usize> {
+    let bytes = table.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+}
+
+fn read_u64(table: &[u8], pos: usize) -> Option<usize> {
+    let bytes = table.get(pos..pos + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?) as usize)
+}
+
+/// Returns the NUL terminated string at `offset` of the strings.
+fn string(table: &'static [u8], offset: usize) -> Option<&'static str> {
+    let strings = read_u32(table, 16)?;
+    let bytes = table.get(strings + offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
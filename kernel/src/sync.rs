@@ -0,0 +1,192 @@
+// Locks for the kernel state shared between the harts and the trap handlers.
+// The data of a lock is only reachable through the guard of [SpinLock::lock], so every access to shared state
+// has to take the lock and the borrow checker sees the rest.
+// The atomics compile to the A extension: `swap` to `amoswap.w.aq`, `compare_exchange` to a lr/sc loop.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::cpu;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+
+/// A lock that spins until it is free.
+/// Must not be taken by a trap handler that may interrupt its holder, see [IrqSpinLock].
+pub struct SpinLock<T> {
+    locked: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Takes the lock, spins while another hart holds it. It is freed when the guard is dropped.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        while self.locked.swap(LOCKED, Ordering::Acquire) == LOCKED {
+            // Only read while waiting, so the holder keeps the cache line.
+            while self.locked.load(Ordering::Relaxed) == LOCKED {
+                spin_loop();
+            }
+        }
+        SpinLockGuard { lock: self }
+    }
+
+    /// Takes the lock, if it is free.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        match self.locked.swap(LOCKED, Ordering::Acquire) {
+            UNLOCKED => Some(SpinLockGuard { lock: self }),
+            _ => None,
+        }
+    }
+
+    /// Frees the lock without its guard, e.g. a guard that was forgotten to keep the lock across a return to user mode.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(UNLOCKED, Ordering::Release);
+    }
+
+    /// Returns the data without taking the lock. Only for code that can't wait for the holder,
+    /// like the panic handler or the firmware, which may have interrupted it.
+    pub unsafe fn force_get(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(UNLOCKED, Ordering::Release);
+    }
+}
+
+/// A [SpinLock] that turns off the interrupts of the hart while it is held,
+/// so a trap handler taking it can't interrupt the holder on the same hart.
+pub struct IrqSpinLock<T> {
+    lock: SpinLock<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        IrqSpinLock {
+            lock: SpinLock::new(data),
+        }
+    }
+
+    /// Turns off the interrupts and takes the lock. Both are restored when the guard is dropped.
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let enabled = cpu::disable_interrupts();
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.lock.lock()),
+            enabled,
+        }
+    }
+
+    /// See [SpinLock::force_get].
+    pub unsafe fn force_get(&self) -> &mut T {
+        self.lock.force_get()
+    }
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+    /// The interrupts were on before the lock was taken.
+    enabled: bool,
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // The lock has to be free before an interrupt can come in.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        cpu::restore_interrupts(self.enabled);
+    }
+}
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is set once and only read afterwards, e.g. state built while the kernel is set up.
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Sets the value with `init` on the first call and returns it.
+    /// Harts calling it while `init` runs wait for it. `init` must not call it again.
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.data.get()).write(init()) };
+                self.state.store(COMPLETE, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != COMPLETE {
+                    spin_loop();
+                }
+            }
+        }
+        unsafe { (*self.data.get()).assume_init_ref() }
+    }
+
+    /// Returns the value, if it is set.
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            COMPLETE => Some(unsafe { (*self.data.get()).assume_init_ref() }),
+            _ => None,
+        }
+    }
+}
@@ -1,27 +1,76 @@
 pub use core::arch::asm;
-use core::ops::Add;
 use riscv_utils::*;
 
+use core::fmt;
+
 use crate::{
     hardware::{
+        clint,
         memory_mapping::MemoryMapping,
         uart::{self},
     },
-    scheduler,
+    log,
+    mmu::EntryBits,
+    perf, platform,
+    scheduler::{self, Prog},
+    user_prog,
 };
 
 fn syscall_from(number: usize) -> SysCall {
     crate::enum_matching!(
         number: SysCall::GetChar,
         SysCall::Print,
+        SysCall::Brk,
+        SysCall::Mmap,
+        SysCall::Munmap,
+        SysCall::Fork,
+        SysCall::Shutdown,
+        SysCall::ReadLog,
+        SysCall::Spawn,
+        SysCall::Trace,
+        SysCall::Wait,
+        SysCall::Counter,
+        SysCall::Profile,
         SysCall::Yield,
         SysCall::Exit
     );
     panic!("Illegal syscall: {}", number);
 }
 
-pub unsafe fn syscall(number: usize, _param_0: usize, _param_1: usize) -> Option<usize> {
-    match syscall_from(number) {
+/// Runs the syscall of the current user prog. If it is traced, the call is logged with its arguments,
+/// its return value and how long it took.
+pub unsafe fn syscall(number: usize, param_0: usize, param_1: usize) -> Option<usize> {
+    let syscall = syscall_from(number);
+    let cur = scheduler::cur();
+    if !cur.is_traced() {
+        return dispatch(syscall, param_0, param_1);
+    }
+    let call = Call {
+        prog: cur,
+        pid: cur.pid(),
+        syscall,
+        param_0,
+        param_1,
+    };
+    // Exit and a successful shutdown do not return.
+    if let SysCall::Exit | SysCall::Shutdown = syscall {
+        crate::info!("{} = ?", call);
+    }
+    let start = clint::time();
+    let ret = dispatch(syscall, param_0, param_1);
+    let micros = (clint::time() - start) * 1_000_000 / platform::get().timebase;
+    if let SysCall::Exit = syscall {
+        return ret;
+    }
+    match ret {
+        Some(ret) => crate::info!("{} = {} <{} us>", call, Value(ret), micros),
+        None => crate::info!("{} <{} us>", call, micros),
+    }
+    ret
+}
+
+unsafe fn dispatch(syscall: SysCall, _param_0: usize, _param_1: usize) -> Option<usize> {
+    match syscall {
         SysCall::GetChar => {
             return sys_get_char();
         }
@@ -29,43 +78,386 @@ pub unsafe fn syscall(number: usize, _param_0: usize, _param_1: usize) -> Option
             sys_print_string(_param_0, _param_1);
             return None;
         }
+        SysCall::Brk => {
+            return Some(scheduler::cur().with_space(|space| space.brk(_param_0)));
+        }
+        SysCall::Mmap => {
+            return Some(sys_mmap(_param_0, _param_1));
+        }
+        SysCall::Munmap => {
+            return Some(sys_munmap(_param_0, _param_1));
+        }
+        SysCall::Fork => {
+            return Some(sys_fork());
+        }
+        SysCall::Shutdown => {
+            return Some(sys_shutdown(_param_0, _param_1));
+        }
+        SysCall::ReadLog => {
+            return Some(sys_read_log(_param_0, _param_1));
+        }
+        SysCall::Spawn => {
+            return Some(sys_spawn(_param_0, _param_1));
+        }
+        SysCall::Trace => {
+            return Some(sys_trace(_param_0, _param_1));
+        }
+        SysCall::Wait => {
+            return Some(sys_wait(_param_0));
+        }
+        SysCall::Counter => {
+            return Some(sys_counter(_param_0, _param_1));
+        }
+        SysCall::Profile => {
+            return Some(perf::print_profile());
+        }
         SysCall::Exit => {
-            exit();
+            exit(_param_0);
             return None;
         }
         SysCall::Yield => {
-            scheduler::cur().increment_mepc();
             sys_yield();
             return None;
         }
     }
 }
 
-unsafe fn exit() {
+unsafe fn exit(status: usize) {
     let cur = scheduler::cur();
+    match status {
+        0 => {}
+        EXIT_PANIC => crate::warn!("User prog {:?} (pid {}) panicked", cur.id(), cur.pid()),
+        _ => crate::info!(
+            "User prog {:?} (pid {}) exited with status {}",
+            cur.id(),
+            cur.pid(),
+            status
+        ),
+    }
+    // Only the user progs started by the kernel are restarted, a panicking one would panic again.
+    if cur.is_forked() || status == EXIT_PANIC {
+        terminate();
+        return;
+    }
     let prog_info = cur.prog_info();
     scheduler::end_prog(scheduler::cur());
     scheduler::init_prog(prog_info);
     sys_yield();
 }
 
+/// Ends the current user prog without restarting it.
+pub unsafe fn terminate() {
+    scheduler::end_prog(scheduler::cur());
+    sys_yield();
+}
+
 unsafe fn sys_get_char() -> Option<usize> {
-    return Some(uart::read_char() as usize);
+    // 0 tells the user prog that nothing was received.
+    return Some(uart::read_byte().map_or(0, |byte| byte as usize));
 }
 
 unsafe fn sys_print_string(str_ptr: usize, size: usize) {
-    // cast to u8 to increment Option<usize> to char pointer
-    let mut str_ptr = str_ptr.clone();
-    for _ in 0..size {
+    let cur = scheduler::cur();
+    // The pointer and the size come from the user prog, nothing is printed if they overflow.
+    let end = match str_ptr.checked_add(size) {
+        Some(end) => end,
+        None => return,
+    };
+    for vaddr in str_ptr..end {
+        // The string lies in the address space of the user prog.
+        let paddr = match cur.translate_read(vaddr) {
+            Some(paddr) => paddr,
+            None => return,
+        };
         // Read value from the pointer with MemoryMapping
-        let char = MemoryMapping::<char>::new(str_ptr as usize).read();
-        uart::print_char(char);
-        str_ptr = str_ptr.add(1);
+        let char = MemoryMapping::<u8>::new(paddr).read();
+        uart::print_char(char as char);
+    }
+}
+
+/// Copies up to `len` bytes of the kernel log, the oldest first, to `buf_ptr`. Returns the number of bytes.
+unsafe fn sys_read_log(buf_ptr: usize, len: usize) -> usize {
+    let cur = scheduler::cur();
+    let mut chunk = [0; 256];
+    let mut copied = 0;
+    while copied < len {
+        let size = (len - copied).min(chunk.len());
+        let count = log::read(copied, &mut chunk[..size]);
+        if count == 0 {
+            break;
+        }
+        for (i, &byte) in chunk[..count].iter().enumerate() {
+            // The buffer lies in the address space of the user prog, its pages may not be mapped yet.
+            let paddr = match buf_ptr
+                .checked_add(copied + i)
+                .and_then(|vaddr| cur.with_space(|space| space.translate_write(vaddr)))
+            {
+                Some(paddr) => paddr,
+                None => return copied + i,
+            };
+            MemoryMapping::<u8>::new(paddr).write(byte);
+        }
+        copied += count;
+    }
+    copied
+}
+
+/// Maps `len` bytes of zeroed memory with the `prot` flags. Returns the start or [MAP_FAILED].
+unsafe fn sys_mmap(len: usize, prot: usize) -> usize {
+    // Reserving address space without access is not supported.
+    // The pmp only allows the user progs to execute their own image.
+    if prot == PROT_NONE || prot & PROT_EXEC != 0 {
+        return MAP_FAILED;
+    }
+    let mut bits = EntryBits::User.val();
+    // Write only pages can't be expressed in a page table entry.
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        bits |= EntryBits::Read.val();
+    }
+    if prot & PROT_WRITE != 0 {
+        bits |= EntryBits::Write.val();
+    }
+    scheduler::cur()
+        .with_space(|space| space.mmap(len, bits))
+        .unwrap_or(MAP_FAILED)
+}
+
+/// Unmaps the page aligned range. Returns 0 or [MAP_FAILED].
+unsafe fn sys_munmap(addr: usize, len: usize) -> usize {
+    if scheduler::cur().with_space(|space| space.munmap(addr, len)) {
+        0
+    } else {
+        MAP_FAILED
+    }
+}
+
+/// Returns the pid of the child or [FORK_FAILED]. The child gets 0.
+unsafe fn sys_fork() -> usize {
+    match scheduler::fork_prog(scheduler::cur()) {
+        Some(child) => child.pid(),
+        None => FORK_FAILED,
+    }
+}
+
+/// Starts the user prog `prog`, e.g. [PROG_USER_1], as a child. Returns its pid or [SPAWN_FAILED].
+/// With [SPAWN_TRACE] in the `flags` its syscalls are logged.
+unsafe fn sys_spawn(prog: usize, flags: usize) -> usize {
+    let prog_info = match user_prog::Info::from_number(prog) {
+        Some(prog_info) if prog_info.is_loaded() => prog_info,
+        _ => return SPAWN_FAILED,
+    };
+    match scheduler::spawn_prog(prog_info, scheduler::cur(), flags & SPAWN_TRACE != 0) {
+        Some(child) => child.pid(),
+        None => SPAWN_FAILED,
+    }
+}
+
+/// Turns the tracing of the user prog `pid` on or off. 0 stands for the caller, the others have to be its children.
+/// Returns 0 or [TRACE_FAILED].
+unsafe fn sys_trace(pid: usize, on: usize) -> usize {
+    let cur = scheduler::cur();
+    let prog = match pid {
+        0 => Some(cur),
+        pid => scheduler::find(pid).filter(|prog| *prog == cur || prog.parent() == Some(cur.pid())),
+    };
+    match prog {
+        Some(prog) => {
+            prog.set_traced(on != 0);
+            0
+        }
+        None => TRACE_FAILED,
+    }
+}
+
+/// Returns [WAIT_RUNNING] while the child `pid` runs and 0 once it has ended,
+/// or [WAIT_FAILED] if `pid` is a user prog, but not a child of the caller.
+unsafe fn sys_wait(pid: usize) -> usize {
+    match scheduler::find(pid) {
+        Some(prog) if prog.parent() == Some(scheduler::cur().pid()) => WAIT_RUNNING,
+        Some(_) => WAIT_FAILED,
+        None => 0,
+    }
+}
+
+/// Returns the counter `which` of the user prog `pid`, 0 stands for the caller, the others have to be its children.
+/// Returns [COUNTER_FAILED] for an unknown counter or pid.
+unsafe fn sys_counter(which: usize, pid: usize) -> usize {
+    let cur = scheduler::cur();
+    let prog = match pid {
+        0 => Some(cur),
+        pid => scheduler::find(pid).filter(|prog| *prog == cur || prog.parent() == Some(cur.pid())),
+    };
+    let (cycles, instret) = match prog {
+        Some(prog) => prog.counters(),
+        None => return COUNTER_FAILED,
+    };
+    match which {
+        COUNTER_CYCLES => cycles,
+        COUNTER_INSTRET => instret,
+        _ => COUNTER_FAILED,
+    }
+}
+
+/// Turns off or resets the machine. Only the user progs started by the kernel are allowed to,
+/// the others get [SHUTDOWN_FAILED].
+unsafe fn sys_shutdown(action: usize, code: usize) -> usize {
+    if scheduler::cur().is_forked() {
+        return SHUTDOWN_FAILED;
+    }
+    match action {
+        SHUTDOWN_POWEROFF => crate::shutdown(code as u16),
+        SHUTDOWN_REBOOT => crate::reboot(),
+        _ => SHUTDOWN_FAILED,
     }
 }
 
 unsafe fn sys_yield() {
-    let next =
-        scheduler::next().expect("No next user prog for system yield. Idle task not implemented");
-    scheduler::switch(next);
+    match scheduler::next() {
+        Some(next) => scheduler::switch(next),
+        // The current user prog keeps running, if it has not ended
+        None if scheduler::has_cur() => {}
+        None => scheduler::idle(),
+    }
+}
+
+fn name(syscall: SysCall) -> &'static str {
+    match syscall {
+        SysCall::GetChar => "get_char",
+        SysCall::Print => "print",
+        SysCall::Brk => "brk",
+        SysCall::Mmap => "mmap",
+        SysCall::Munmap => "munmap",
+        SysCall::Fork => "fork",
+        SysCall::Shutdown => "shutdown",
+        SysCall::ReadLog => "read_log",
+        SysCall::Spawn => "spawn",
+        SysCall::Trace => "trace",
+        SysCall::Wait => "wait",
+        SysCall::Counter => "counter",
+        SysCall::Profile => "profile",
+        SysCall::Yield => "yield",
+        SysCall::Exit => "exit",
+    }
+}
+
+/// A syscall of a traced user prog with its decoded arguments, e.g. `[pid 3] mmap(8192, READ|WRITE)`.
+struct Call {
+    prog: Prog,
+    pid: usize,
+    syscall: SysCall,
+    param_0: usize,
+    param_1: usize,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (param_0, param_1) = (self.param_0, self.param_1);
+        write!(f, "[pid {}] {}(", self.pid, name(self.syscall))?;
+        match self.syscall {
+            SysCall::GetChar | SysCall::Fork | SysCall::Profile | SysCall::Yield => Ok(()),
+            SysCall::Print => {
+                let text = UserText {
+                    prog: self.prog,
+                    addr: param_0,
+                    len: param_1,
+                };
+                write!(f, "{}, {}", text, param_1)
+            }
+            SysCall::Brk => write!(f, "0x{:x}", param_0),
+            SysCall::Mmap => write!(f, "{}, {}", param_0, Prot(param_1)),
+            SysCall::Munmap | SysCall::ReadLog => write!(f, "0x{:x}, {}", param_0, param_1),
+            SysCall::Shutdown => match param_0 {
+                SHUTDOWN_POWEROFF => write!(f, "POWEROFF, {}", param_1),
+                SHUTDOWN_REBOOT => write!(f, "REBOOT"),
+                action => write!(f, "{}, {}", action, param_1),
+            },
+            SysCall::Spawn => {
+                match user_prog::Info::from_number(param_0) {
+                    Some(prog_info) => write!(f, "{}", prog_info.id.image())?,
+                    None => write!(f, "{}", param_0)?,
+                }
+                match param_1 {
+                    SPAWN_TRACE => write!(f, ", TRACE"),
+                    flags => write!(f, ", 0x{:x}", flags),
+                }
+            }
+            SysCall::Trace => write!(f, "{}, {}", param_0, param_1 != 0),
+            SysCall::Counter => match param_0 {
+                COUNTER_CYCLES => write!(f, "CYCLES, {}", param_1),
+                COUNTER_INSTRET => write!(f, "INSTRET, {}", param_1),
+                which => write!(f, "{}, {}", which, param_1),
+            },
+            SysCall::Wait | SysCall::Exit => write!(f, "{}", param_0),
+        }?;
+        write!(f, ")")
+    }
+}
+
+/// A return value, the failures are printed as -1 and addresses in hex.
+struct Value(usize);
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            usize::MAX => write!(f, "-1"),
+            value if value >= 0x1000 => write!(f, "0x{:x}", value),
+            value => write!(f, "{}", value),
+        }
+    }
+}
+
+/// The flags of [SysCall::Mmap], e.g. `READ|WRITE`.
+struct Prot(usize);
+
+impl fmt::Display for Prot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == PROT_NONE {
+            return write!(f, "NONE");
+        }
+        let flags = [
+            (PROT_READ, "READ"),
+            (PROT_WRITE, "WRITE"),
+            (PROT_EXEC, "EXEC"),
+        ];
+        let mut separator = "";
+        for (flag, name) in flags {
+            if self.0 & flag != 0 {
+                write!(f, "{}{}", separator, name)?;
+                separator = "|";
+            }
+        }
+        match self.0 & !(PROT_READ | PROT_WRITE | PROT_EXEC) {
+            0 => Ok(()),
+            unknown => write!(f, "{}0x{:x}", separator, unknown),
+        }
+    }
+}
+
+/// Bytes the trace shows of a printed text.
+const MAX_TRACED_TEXT: usize = 32;
+
+/// Text in the address space of a user prog, quoted and escaped like a Rust string.
+struct UserText {
+    prog: Prog,
+    addr: usize,
+    len: usize,
+}
+
+impl fmt::Display for UserText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        let end = self.addr.saturating_add(self.len.min(MAX_TRACED_TEXT));
+        for vaddr in self.addr..end {
+            let byte = match self.prog.translate_read(vaddr) {
+                Some(paddr) => unsafe { MemoryMapping::<u8>::new(paddr).read() },
+                None => break,
+            };
+            write!(f, "{}", (byte as char).escape_debug())?;
+        }
+        write!(f, "\"")?;
+        if self.len > MAX_TRACED_TEXT {
+            write!(f, "...")?;
+        }
+        Ok(())
+    }
 }
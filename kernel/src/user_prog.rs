@@ -1,23 +1,107 @@
+/// Start of the user prog windows, `_user_start` in the linker script of the kernel.
+#[cfg(any(not(feature = "sbi"), feature = "builtin-sbi"))]
+const USER_START: usize = 0x8010_0000;
+#[cfg(all(feature = "sbi", not(feature = "builtin-sbi")))]
+const USER_START: usize = 0x8030_0000;
+
 pub const USER1: Info = Info {
     id: Id::User1,
-    boot_mepc: 0x80100000,
-    pmp_idx: 0,
+    load_addr: USER_START,
 };
 
 pub const USER2: Info = Info {
     id: Id::User2,
-    boot_mepc: 0x80200000,
-    pmp_idx: 1,
+    load_addr: USER_START + WINDOW_SIZE,
+};
+
+/// Started at boot, if it was loaded as well.
+pub const STRACE: Info = Info {
+    id: Id::Strace,
+    load_addr: USER_START + 2 * WINDOW_SIZE,
 };
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Id {
     User1,
     User2,
+    Strace,
+}
+impl Id {
+    /// Name of the image, as the binary is called.
+    pub fn image(&self) -> &'static str {
+        match self {
+            Id::User1 => "user_1",
+            Id::User2 => "user_2",
+            Id::Strace => "strace",
+        }
+    }
 }
 #[derive(PartialEq, Clone, Copy)]
 pub struct Info {
     pub id: Id,
-    pub boot_mepc: usize,
-    pub pmp_idx: usize,
+    /// Physical address the image is loaded to.
+    pub load_addr: usize,
+}
+
+/// Size of the memory window every user prog is loaded into.
+pub const WINDOW_SIZE: usize = 0x10_0000;
+
+/// The bytes "userprog" in little endian, at the start of every user image.
+const MAGIC: usize = 0x676f_7270_7265_7375;
+
+/// Layout of a user image, written to its start by `user_shared/src/asm/user.S`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Header {
+    magic: usize,
+    /// Virtual address the image is linked to. All addresses of the header are virtual.
+    pub base: usize,
+    pub entry: usize,
+    /// End of the code and read only data, the writable data follows.
+    pub text_end: usize,
+    pub image_end: usize,
+}
+
+impl Info {
+    /// Returns the user prog [riscv_utils::SysCall::Spawn] starts for `prog`, e.g. [riscv_utils::PROG_USER_1].
+    pub fn from_number(prog: usize) -> Option<Info> {
+        match prog {
+            riscv_utils::PROG_USER_1 => Some(USER1),
+            riscv_utils::PROG_USER_2 => Some(USER2),
+            riscv_utils::PROG_STRACE => Some(STRACE),
+            _ => None,
+        }
+    }
+
+    /// Returns true if an image was loaded into the window.
+    pub fn is_loaded(&self) -> bool {
+        unsafe { (self.load_addr as *const usize).read_volatile() == MAGIC }
+    }
+
+    /// Reads the header of the loaded image.
+    pub fn header(&self) -> Header {
+        let header = unsafe { (self.load_addr as *const Header).read_volatile() };
+        assert!(
+            header.magic == MAGIC,
+            "No user prog: {:?} loaded at 0x{:x}",
+            self.id,
+            self.load_addr
+        );
+        assert!(
+            header.base < header.entry
+                && header.entry < header.text_end
+                && header.text_end <= header.image_end
+                && header.image_end - header.base <= WINDOW_SIZE,
+            "Invalid header of user prog: {:?}",
+            self.id
+        );
+        header
+    }
+}
+
+impl Header {
+    /// Returns the offset of the virtual address `vaddr` in the image.
+    pub fn offset(&self, vaddr: usize) -> usize {
+        vaddr - self.base
+    }
 }
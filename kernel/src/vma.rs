@@ -0,0 +1,485 @@
+use crate::{
+    cpu::{self, SatpMode},
+    ipi, kmem,
+    mmu::{self, EntryBits, Table},
+    page,
+    user_prog::Header,
+};
+
+/// Top of the user stack. The stack grows down from here.
+pub const USER_STACK_TOP: usize = 0x3f_ffff_f000;
+/// Maximum size the user stack can grow to.
+pub const USER_STACK_SIZE: usize = 0x10_0000;
+/// Start of the user heap.
+pub const USER_HEAP_START: usize = 0x1_0000_0000;
+/// Anonymous mappings are placed from here on. The heap can grow up to here.
+pub const USER_MMAP_BASE: usize = 0x20_0000_0000;
+
+const MAX_VMAS: usize = 16;
+
+/// The kind of access that caused a page fault.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Execute,
+    Load,
+    Store,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    /// The loaded program, mapped when the address space is created.
+    Image,
+    /// Pages are allocated when they are first accessed.
+    Stack,
+    Heap,
+    /// Created by the mmap syscall.
+    Anon,
+}
+
+/// A virtual memory area, a region of the address space with the same permissions.
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// Page table entry bits of the mapped pages.
+    pub bits: usize,
+    pub kind: Kind,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+    /// Returns true if the pages of the area are allocated on demand.
+    pub fn is_demand(&self) -> bool {
+        self.kind != Kind::Image
+    }
+    pub fn allows(&self, access: Access) -> bool {
+        let bit = match access {
+            Access::Execute => EntryBits::Execute,
+            Access::Load => EntryBits::Read,
+            Access::Store => EntryBits::Write,
+        };
+        self.bits & bit.val() != 0
+    }
+    /// Returns the parts of the area in front of and behind the range from `start` to `end`.
+    pub fn without(&self, start: usize, end: usize) -> [Option<Vma>; 2] {
+        let front = Vma {
+            end: start.min(self.end),
+            ..*self
+        };
+        let back = Vma {
+            start: end.max(self.start),
+            ..*self
+        };
+        [front, back].map(|part| Some(part).filter(|part| part.start < part.end))
+    }
+}
+
+/// The address space of a user prog: its page table and the list of valid areas.
+pub struct AddressSpace {
+    root: *mut Table,
+    asid: usize,
+    vmas: [Option<Vma>; MAX_VMAS],
+}
+
+/// The page tables belong to the address space, it may be handed to another hart with its user prog.
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    /// Creates an address space with the program image loaded to `load_addr` and an empty stack and heap.
+    /// The code of the image is executable, its data is writable and copied on the first store.
+    pub fn new(asid: usize, load_addr: usize, header: &Header) -> Self {
+        let root = page::zalloc(1) as *mut Table;
+        assert!(!root.is_null(), "Out of memory for a user page table");
+        let mut space = AddressSpace {
+            root,
+            asid,
+            vmas: [None; MAX_VMAS],
+        };
+        #[cfg(feature = "sbi")]
+        crate::kmem::share(space.table());
+        let image = [
+            (
+                header.base,
+                header.text_end,
+                EntryBits::UserReadExecute.val(),
+            ),
+            (
+                header.text_end,
+                header.image_end,
+                EntryBits::UserReadWrite.val(),
+            ),
+        ];
+        for (start, end, bits) in image.into_iter().filter(|(start, end, _)| start < end) {
+            space.insert(Vma {
+                start,
+                end,
+                bits,
+                kind: Kind::Image,
+            });
+            // The image is mapped from the address it is linked to, to the address it is loaded to.
+            // Its data is read only until the first store copies it, see [Self::copy_on_write].
+            // So every user prog of the image starts on the loaded data, even after a restart.
+            let mapped = bits & !EntryBits::Write.val();
+            for addr in (start..end).step_by(page::PAGE_SIZE) {
+                let paddr = load_addr + header.offset(addr);
+                mmu::map(space.table(), addr, paddr, mapped, 0);
+            }
+        }
+        space.insert(Vma {
+            start: USER_STACK_TOP - USER_STACK_SIZE,
+            end: USER_STACK_TOP,
+            bits: EntryBits::UserReadWrite.val(),
+            kind: Kind::Stack,
+        });
+        space.insert(Vma {
+            start: USER_HEAP_START,
+            end: USER_HEAP_START,
+            bits: EntryBits::UserReadWrite.val(),
+            kind: Kind::Heap,
+        });
+        // The asid may have been used by an ended user prog.
+        ipi::flush_asid(asid);
+        space
+    }
+
+    fn table(&mut self) -> &mut Table {
+        unsafe { &mut *self.root }
+    }
+
+    /// Returns the value for the satp register to switch to this address space.
+    pub fn satp(&self) -> usize {
+        cpu::build_satp(SatpMode::Sv39, self.asid, self.root as usize)
+    }
+
+    /// Adds an area. Returns false if there is no free slot.
+    pub fn insert(&mut self, vma: Vma) -> bool {
+        match self.vmas.iter_mut().find(|vma| vma.is_none()) {
+            Some(slot) => {
+                *slot = Some(vma);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the area containing `addr`.
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.vmas.iter().flatten().find(|vma| vma.contains(addr))
+    }
+
+    fn find_kind(&mut self, kind: Kind) -> Option<&mut Vma> {
+        self.vmas.iter_mut().flatten().find(|vma| vma.kind == kind)
+    }
+
+    /// Sets the end of the heap to `addr` and returns the new end.
+    /// If `addr` is outside of the possible heap range or the heap would reach the kernel mapping, the end is not changed.
+    pub fn brk(&mut self, addr: usize) -> usize {
+        let heap = *self.find_kind(Kind::Heap).expect("User prog has no heap");
+        if addr < heap.start || addr > USER_MMAP_BASE || kmem::overlaps_kernel(heap.start, addr) {
+            return heap.end;
+        }
+        // Free the pages which are not part of the heap anymore.
+        let new_end = page::align_val(addr, 12);
+        if new_end < heap.end {
+            self.free_range(new_end, heap.end);
+        }
+        self.find_kind(Kind::Heap).unwrap().end = addr;
+        addr
+    }
+
+    /// Creates an anonymous area of at least `len` bytes with the page table entry `bits`.
+    /// Returns the start of the area.
+    pub fn mmap(&mut self, len: usize, bits: usize) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+        // The length comes from the user prog, it may be close to usize::MAX.
+        let len = len.checked_add(page::PAGE_SIZE - 1)? & !(page::PAGE_SIZE - 1);
+        // Take the first gap behind USER_MMAP_BASE, that is large enough.
+        let mut start = USER_MMAP_BASE;
+        let end = loop {
+            let end = start.checked_add(len)?;
+            match self
+                .vmas
+                .iter()
+                .flatten()
+                .find(|vma| vma.start < end && start < vma.end)
+            {
+                Some(vma) => start = page::align_val(vma.end, 12),
+                None => break end,
+            }
+        };
+        if end > USER_STACK_TOP - USER_STACK_SIZE || kmem::overlaps_kernel(start, end) {
+            return None;
+        }
+        let vma = Vma {
+            start,
+            end,
+            bits,
+            kind: Kind::Anon,
+        };
+        if self.insert(vma) {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Removes the page aligned range from the anonymous areas.
+    /// Returns false if the range is not page aligned, lies outside of the user address space
+    /// or would split an area without a free slot for the second part.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> bool {
+        if addr % page::PAGE_SIZE != 0 || len == 0 {
+            return false;
+        }
+        let end = match addr.checked_add(len) {
+            Some(end) if end <= USER_STACK_TOP => page::align_val(end, 12),
+            _ => return false,
+        };
+        for i in 0..MAX_VMAS {
+            let vma = match self.vmas[i] {
+                Some(vma) if vma.kind == Kind::Anon && vma.start < end && addr < vma.end => vma,
+                _ => continue,
+            };
+            let parts = vma.without(addr, end);
+            // The first part takes the slot of the area, the second one needs a free slot.
+            // Only a range inside of a single area splits it, no other area has been changed yet.
+            if parts.iter().all(Option::is_some) && !self.vmas.iter().any(Option::is_none) {
+                return false;
+            }
+            self.free_range(addr.max(vma.start), end.min(vma.end));
+            self.vmas[i] = None;
+            // Keep the parts in front of and behind the range.
+            for part in parts.into_iter().flatten() {
+                self.insert(part);
+            }
+        }
+        true
+    }
+
+    /// Unmaps the pages from `start` to `end` and frees the ones owned by the address space.
+    fn free_range(&mut self, start: usize, end: usize) {
+        let asid = self.asid;
+        mmu::for_each_page(self.table(), start, end, &mut |addr, entry| {
+            let page = entry.get_physical_address();
+            entry.set_entry(0);
+            // The pages of the loaded image are not part of the page allocator.
+            if page::owns(page) {
+                page::release(page);
+            }
+            ipi::flush_page(addr, asid);
+        });
+    }
+
+    /// Creates a copy of the address space with the given `asid`.
+    /// The mapped pages are shared read only by both address spaces and copied on the first store.
+    /// The pages of the loaded image stay shared with the copy until it writes to them.
+    pub fn fork(&mut self, asid: usize) -> Option<AddressSpace> {
+        let root = page::zalloc(1) as *mut Table;
+        if root.is_null() {
+            return None;
+        }
+        let mut child = AddressSpace {
+            root,
+            asid,
+            vmas: self.vmas,
+        };
+        #[cfg(feature = "sbi")]
+        crate::kmem::share(child.table());
+        let vmas = self.vmas;
+        for vma in vmas.iter().flatten() {
+            let bits = vma.bits & !EntryBits::Write.val();
+            let child_table = child.table();
+            // Only the mapped pages are visited, the heap and the mmap areas may be large.
+            mmu::for_each_page(self.table(), vma.start, vma.end, &mut |addr, entry| {
+                let page = entry.get_physical_address();
+                if page::owns(page) {
+                    page::share(page);
+                }
+                entry.set_entry(entry.get_entry() & !EntryBits::Write.val());
+                mmu::map(child_table, addr, page, bits, 0);
+            });
+        }
+        ipi::flush_asid(self.asid);
+        ipi::flush_asid(asid);
+        Some(child)
+    }
+
+    /// Gives the address space its own copy of the shared page at `addr`.
+    fn copy_on_write(&mut self, addr: usize, vma: &Vma) -> bool {
+        let page = self.translate(addr).unwrap();
+        if page::owns(page) && !page::is_shared(page) {
+            // The other owners already made their copies.
+            mmu::map(self.table(), addr, page, vma.bits, 0);
+        } else {
+            // The pages of the loaded image are always copied, they stay in place for the other owners.
+            let copy = page::alloc(1);
+            if copy.is_null() {
+                return false;
+            }
+            unsafe {
+                core::ptr::copy_nonoverlapping(page as *const u8, copy, page::PAGE_SIZE);
+            }
+            mmu::map(self.table(), addr, copy as usize, vma.bits, 0);
+            if page::owns(page) {
+                page::release(page);
+            }
+        }
+        ipi::flush_page(addr, self.asid);
+        true
+    }
+
+    /// Returns the physical address of `vaddr` if it is mapped.
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        mmu::virt_to_phys(unsafe { &*self.root }, vaddr)
+    }
+
+    /// Returns the physical address of `vaddr` for a read of the kernel on behalf of the user prog.
+    /// Returns None if the prog may not read it.
+    pub fn translate_read(&self, vaddr: usize) -> Option<usize> {
+        // The kernel is mapped in every address space as well, without the user bit.
+        let user_read = EntryBits::Read.val() | EntryBits::User.val();
+        let bits = mmu::entry_bits(unsafe { &*self.root }, vaddr)?;
+        if bits & user_read != user_read {
+            return None;
+        }
+        self.translate(vaddr)
+    }
+
+    /// Returns the physical address of `vaddr` for a write of the kernel on behalf of the user prog.
+    /// The page is mapped or copied first, like on a store page fault. Returns None if the prog may not write it.
+    pub fn translate_write(&mut self, vaddr: usize) -> Option<usize> {
+        // The kernel is mapped in every address space as well, without the user bit.
+        let user_write = EntryBits::Write.val() | EntryBits::User.val();
+        let writable = mmu::entry_bits(unsafe { &*self.root }, vaddr)
+            .map_or(false, |bits| bits & user_write == user_write);
+        if !writable && !self.handle_fault(vaddr, Access::Store) {
+            return None;
+        }
+        self.translate(vaddr)
+    }
+
+    /// Maps a zeroed page at `addr` if it lies in a demand paged area that allows the `access`,
+    /// or copies the page if it is shared after a fork.
+    /// Returns false if the access is invalid.
+    pub fn handle_fault(&mut self, addr: usize, access: Access) -> bool {
+        let vma = match self.find(addr) {
+            Some(vma) => *vma,
+            None => return false,
+        };
+        let page_addr = addr & !(page::PAGE_SIZE - 1);
+        if !vma.allows(access) {
+            return false;
+        }
+        // Pages are mapped with the bits of the area, except for shared pages, which are read only.
+        if self.translate(page_addr).is_some() {
+            return access == Access::Store && self.copy_on_write(page_addr, &vma);
+        }
+        if !vma.is_demand() || kmem::overlaps_kernel(page_addr, page_addr + page::PAGE_SIZE) {
+            return false;
+        }
+        let page = page::zalloc(1);
+        if page.is_null() {
+            return false;
+        }
+        mmu::map(self.table(), page_addr, page as usize, vma.bits, 0);
+        ipi::flush_page(page_addr, self.asid);
+        true
+    }
+
+    /// Frees the owned pages, the page tables and the root table.
+    pub fn free(&mut self) {
+        for i in 0..MAX_VMAS {
+            if let Some(vma) = self.vmas[i] {
+                self.free_range(vma.start, vma.end);
+            }
+        }
+        // The tables of the kernel are not owned by the address space.
+        #[cfg(feature = "sbi")]
+        crate::kmem::unshare(self.table());
+        mmu::unmap(self.table());
+        page::dealloc(self.root as *mut u8);
+        ipi::flush_asid(self.asid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: Vma = Vma {
+        start: 0x1000,
+        end: 0x5000,
+        bits: 0,
+        kind: Kind::Anon,
+    };
+
+    fn bounds(parts: [Option<Vma>; 2]) -> [Option<(usize, usize)>; 2] {
+        parts.map(|part| part.map(|vma| (vma.start, vma.end)))
+    }
+
+    #[test]
+    fn split_in_the_middle() {
+        let parts = AREA.without(0x2000, 0x3000);
+        assert_eq!(
+            bounds(parts),
+            [Some((0x1000, 0x2000)), Some((0x3000, 0x5000))]
+        );
+        // The parts keep the permissions and the kind.
+        assert!(parts
+            .iter()
+            .flatten()
+            .all(|vma| vma.kind == Kind::Anon && vma.bits == 0));
+    }
+
+    #[test]
+    fn cut_at_the_ends() {
+        assert_eq!(
+            bounds(AREA.without(0x1000, 0x2000)),
+            [None, Some((0x2000, 0x5000))]
+        );
+        assert_eq!(
+            bounds(AREA.without(0x0, 0x2000)),
+            [None, Some((0x2000, 0x5000))]
+        );
+        assert_eq!(
+            bounds(AREA.without(0x4000, 0x5000)),
+            [Some((0x1000, 0x4000)), None]
+        );
+        assert_eq!(
+            bounds(AREA.without(0x4000, 0x9000)),
+            [Some((0x1000, 0x4000)), None]
+        );
+    }
+
+    #[test]
+    fn removed_completely() {
+        assert_eq!(bounds(AREA.without(0x1000, 0x5000)), [None, None]);
+        assert_eq!(bounds(AREA.without(0, usize::MAX)), [None, None]);
+    }
+
+    #[test]
+    fn range_outside_keeps_the_area() {
+        let whole = [Some((0x1000, 0x5000)), None];
+        assert_eq!(bounds(AREA.without(0x5000, 0x6000)), whole);
+        assert_eq!(
+            bounds(AREA.without(0, 0x1000)),
+            [None, Some((0x1000, 0x5000))]
+        );
+    }
+
+    #[test]
+    fn permissions() {
+        let vma = Vma {
+            bits: EntryBits::UserReadWrite.val(),
+            ..AREA
+        };
+        assert!(vma.allows(Access::Load) && vma.allows(Access::Store));
+        assert!(!vma.allows(Access::Execute));
+        assert!(vma.contains(0x1000) && vma.contains(0x4fff));
+        assert!(!vma.contains(0xfff) && !vma.contains(0x5000));
+        assert!(vma.is_demand());
+    }
+}
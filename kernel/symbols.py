@@ -0,0 +1,158 @@
+#!/usr/bin/env python3
+# Post-link step: writes the function symbols of the kernel and the user programs into the symbol table
+# the kernel reserves in its image (KERNEL_SYMBOLS in kernel/src/symbols.rs), so it can name addresses itself.
+# Run it on the linked kernel ELF before `cargo objcopy` makes the binary:
+#   python3 kernel/symbols.py target/riscv64gc-unknown-none-elf/debug/riscv_rust_os \
+#       target/riscv64gc-unknown-none-elf/debug/user_1 target/riscv64gc-unknown-none-elf/debug/user_2
+# User programs that were not built yet are skipped. The kernel ELF is changed in place.
+#
+# Layout of the table, little endian:
+#   magic "symbols\0", u32 image count, u32 entry count, u32 offset of the strings, u32 reserved
+#   per image: u32 name, u32 first entry, u32 entry count
+#   per entry, sorted by address within an image: u64 address, u32 size, u32 name
+#   strings, NUL terminated, names are offsets into them
+import os
+import re
+import struct
+import sys
+
+TABLE_SYMBOL = "KERNEL_SYMBOLS"
+MAGIC = b"symbols\0"
+HEADER = struct.Struct("<8sIIII")
+IMAGE = struct.Struct("<III")
+ENTRY = struct.Struct("<QII")
+
+SHT_SYMTAB = 2
+SHF_EXECINSTR = 0x4
+STT_NOTYPE = 0
+STT_FUNC = 2
+# Dropped first, if the table does not fit
+LIBRARY_PREFIXES = ("core::", "alloc::", "compiler_builtins::", "<core::", "<alloc::")
+
+
+class Elf:
+    def __init__(self, path):
+        with open(path, "rb") as file:
+            self.data = bytearray(file.read())
+        if self.data[:4] != b"\x7fELF" or self.data[4] != 2 or self.data[5] != 1:
+            sys.exit(f"{path}: not a 64 bit little endian ELF")
+        shoff, = struct.unpack_from("<Q", self.data, 0x28)
+        shentsize, shnum = struct.unpack_from("<HH", self.data, 0x3A)
+        self.sections = []
+        for i in range(shnum):
+            (_, sh_type, flags, addr, offset, size, link) = struct.unpack_from(
+                "<IIQQQQI", self.data, shoff + i * shentsize
+            )
+            self.sections.append((sh_type, flags, addr, offset, size, link))
+
+    def symbols(self):
+        """Yields name, value, size, type and section index of every symbol."""
+        for sh_type, _, _, offset, size, link in self.sections:
+            if sh_type != SHT_SYMTAB:
+                continue
+            strtab = self.sections[link][3]
+            for pos in range(offset, offset + size, 24):
+                name, info, _, shndx, value, sym_size = struct.unpack_from("<IBBHQQ", self.data, pos)
+                end = self.data.index(b"\0", strtab + name)
+                yield self.data[strtab + name:end].decode(errors="replace"), value, sym_size, info & 0xF, shndx
+
+    def functions(self):
+        """Returns the functions and the labels in code sections as sorted (address, size, name)."""
+        found = {}
+        for name, value, size, sym_type, shndx in self.symbols():
+            if not name or name.startswith((".L", "$")) or shndx == 0 or shndx >= len(self.sections):
+                continue
+            is_code = self.sections[shndx][1] & SHF_EXECINSTR
+            if sym_type == STT_FUNC or (sym_type == STT_NOTYPE and is_code):
+                # Functions win over labels at the same address
+                if value not in found or sym_type == STT_FUNC:
+                    found[value] = (size, demangle(name))
+        functions = sorted((addr, size, name) for addr, (size, name) in found.items())
+        # Labels of assembly files have no size, they reach up to the next symbol
+        for i, (addr, size, name) in enumerate(functions):
+            if size == 0 and i + 1 < len(functions):
+                functions[i] = (addr, functions[i + 1][0] - addr, name)
+        return functions
+
+    def file_offset(self, symbol):
+        """Returns the file offset and size of `symbol`."""
+        for name, value, size, _, shndx in self.symbols():
+            if name == symbol and 0 < shndx < len(self.sections):
+                _, _, addr, offset, _, _ = self.sections[shndx]
+                return offset + value - addr, size
+        sys.exit(f"The kernel has no {symbol}, is it built from this tree?")
+
+
+ESCAPES = {"$LT$": "<", "$GT$": ">", "$RF$": "&", "$BP$": "*", "$C$": ",", "$SP$": "@",
+           "$u20$": " ", "$u27$": "'", "$u5b$": "[", "$u5d$": "]", "$u7b$": "{", "$u7d$": "}",
+           "$u7e$": "~", "$u3b$": ";", "$u2b$": "+", "$u22$": '"'}
+
+
+def demangle(name):
+    """Demangles a legacy Rust symbol, e.g. _ZN13riscv_rust_os9scheduler6switch17h0123456789abcdefE."""
+    match = re.fullmatch(r"_?_ZN(.*)E", name)
+    if not match:
+        return name
+    rest, parts = match.group(1), []
+    while rest and rest[0].isdigit():
+        digits = re.match(r"\d+", rest).group()
+        length = int(digits)
+        parts.append(rest[len(digits):len(digits) + length])
+        rest = rest[len(digits) + length:]
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    text = "::".join(parts)
+    for escape, char in ESCAPES.items():
+        text = text.replace(escape, char)
+    return text.replace("..", "::")
+
+
+def build(images):
+    strings = bytearray()
+    offsets = {}
+
+    def string(text):
+        if text not in offsets:
+            offsets[text] = len(strings)
+            strings.extend(text.encode() + b"\0")
+        return offsets[text]
+
+    image_part, entry_part, count = bytearray(), bytearray(), 0
+    for image, functions in images:
+        image_part += IMAGE.pack(string(image), count, len(functions))
+        for addr, size, name in functions:
+            entry_part += ENTRY.pack(addr, min(size, 0xFFFF_FFFF), string(name))
+        count += len(functions)
+    strings_offset = HEADER.size + len(image_part) + len(entry_part)
+    header = HEADER.pack(MAGIC, len(images), count, strings_offset, 0)
+    return header + image_part + entry_part + strings
+
+
+def main():
+    if len(sys.argv) < 2:
+        sys.exit(f"usage: {sys.argv[0]} KERNEL_ELF [USER_ELF...]")
+    kernel = Elf(sys.argv[1])
+    images = [("kernel", kernel.functions())]
+    for path in sys.argv[2:]:
+        if not os.path.exists(path):
+            print(f"symbols.py: {path} is not built, skipped")
+            continue
+        images.append((os.path.basename(path), Elf(path).functions()))
+    offset, size = kernel.file_offset(TABLE_SYMBOL)
+    table = build(images)
+    if len(table) > size:
+        # Keep the functions of the kernel and the user programs themselves
+        images = [(image, [f for f in functions if not f[2].startswith(LIBRARY_PREFIXES)])
+                  for image, functions in images]
+        table = build(images)
+    if len(table) > size:
+        sys.exit(f"The symbol table needs {len(table)} bytes, raise TABLE_SIZE in kernel/src/symbols.rs")
+    kernel.data[offset:offset + size] = table + bytes(size - len(table))
+    with open(sys.argv[1], "wb") as file:
+        file.write(kernel.data)
+    count = sum(len(functions) for _, functions in images)
+    print(f"symbols.py: {count} symbols of {len(images)} image(s), {len(table)} of {size} bytes")
+
+
+if __name__ == "__main__":
+    main()
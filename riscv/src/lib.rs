@@ -3,7 +3,7 @@
 mod reg;
 mod sys_call;
 pub use reg::*;
-pub use sys_call::SysCall;
+pub use sys_call::*;
 
 #[macro_export]
 macro_rules! read_machine_reg {
@@ -13,8 +13,19 @@ type RegEnt = RegisterEntry;
 ///
 ///     `mpp`: sets Previous Privilege Mode to User-mode so modules run only in U-mode after setup
 pub const MSTATUS_MPP_U: (RegEnt, RegEnt) = ((11, false), (12, false));
+///     `mpp`: sets Previous Privilege Mode to Supervisor-mode so mret returns to a kernel in S-mode
+pub const MSTATUS_MPP_S: (RegEnt, RegEnt) = ((11, true), (12, false));
 ///     `mie`: machine-mode interrupt enable
 pub const MSTATUS_MIE: RegEnt = (3, true);
+///     `mpie`: machine-mode interrupt enable after mret
+pub const MSTATUS_MPIE: RegEnt = (7, true);
+
+/// sstatus: supervisor status
+///
+///     `spp`: sets Previous Privilege Mode to User-mode so modules run only in U-mode after setup
+pub const SSTATUS_SPP_U: RegEnt = (8, false);
+///     `sie`: supervisor-mode interrupt enable
+pub const SSTATUS_SIE: RegEnt = (1, true);
 
 //  mie
 ///     `meie`: external machine-mode interrupt enable
@@ -33,6 +44,9 @@ pub const SIE_STIE: RegEnt = (5, true);
 pub const SIE_SSIE: RegEnt = (1, true);
 
 //  clint - Core Local Interrupt
+///     `msip_addr`: Address of the software interrupt pending bits of the harts, one 32-bit register per hart
+pub const MSIP_ADDR: usize = 0x0200_0000;
+
 ///     `mtimecmp_addr`: Address of the Compare Value for the Core Local Interrupt (clint), triggers timer interrupt **!! In QEMU at 0x0200 on real hardware at 0x2000**
 pub const MTIMECMP_ADDR: usize = 0x0200_4000;
 
@@ -1,6 +1,66 @@
+#[derive(Clone, Copy)]
 pub enum SysCall {
     GetChar = 0,
     Print = 1,
+    Brk = 2,
+    Mmap = 3,
+    Munmap = 4,
+    Fork = 5,
+    Shutdown = 6,
+    ReadLog = 7,
+    Spawn = 8,
+    Trace = 9,
+    Wait = 10,
+    Counter = 11,
+    Profile = 12,
     Yield = 23,
     Exit = 42,
 }
+
+/// Memory protection flags of [SysCall::Mmap].
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// Returned by [SysCall::Mmap] and [SysCall::Munmap] if the call failed.
+pub const MAP_FAILED: usize = usize::MAX;
+
+/// Exit status of [SysCall::Exit] for a user prog that panicked, like the one of Rust programs on Linux.
+pub const EXIT_PANIC: usize = 101;
+
+/// Returned by [SysCall::Fork] if no user prog could be created.
+pub const FORK_FAILED: usize = usize::MAX;
+
+/// Actions of [SysCall::Shutdown], the second parameter is the exit code of a poweroff.
+pub const SHUTDOWN_POWEROFF: usize = 0;
+pub const SHUTDOWN_REBOOT: usize = 1;
+
+/// Returned by [SysCall::Shutdown] if the user prog is not allowed to turn off the machine.
+pub const SHUTDOWN_FAILED: usize = usize::MAX;
+
+/// The user progs [SysCall::Spawn] starts, one for each image the kernel knows.
+pub const PROG_USER_1: usize = 0;
+pub const PROG_USER_2: usize = 1;
+pub const PROG_STRACE: usize = 2;
+
+/// Flag of [SysCall::Spawn], the syscalls of the new user prog are written to the kernel log.
+pub const SPAWN_TRACE: usize = 1 << 0;
+
+/// Returned by [SysCall::Spawn] if the image is not loaded or no user prog could be created.
+pub const SPAWN_FAILED: usize = usize::MAX;
+
+/// Returned by [SysCall::Trace] if the pid is neither the caller nor one of its children.
+pub const TRACE_FAILED: usize = usize::MAX;
+
+/// Returned by [SysCall::Wait] while the child runs. 0 is returned once it has ended.
+pub const WAIT_RUNNING: usize = 1;
+/// Returned by [SysCall::Wait] if the pid belongs to a user prog, which is not a child of the caller.
+pub const WAIT_FAILED: usize = usize::MAX;
+
+/// The counters [SysCall::Counter] returns, counted while the user prog ran in U-mode.
+pub const COUNTER_CYCLES: usize = 0;
+pub const COUNTER_INSTRET: usize = 1;
+
+/// Returned by [SysCall::Counter] if the counter is unknown or the pid is neither the caller nor one of its children.
+pub const COUNTER_FAILED: usize = usize::MAX;
@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+// Runs user_1 with its syscalls traced. The kernel writes each of them to its log,
+// with the arguments, the return value and how long it took.
+use riscv_utils::{PROG_USER_1, SPAWN_TRACE};
+use sys_call as sys;
+use user_shared::*;
+
+#[no_mangle]
+extern "C" fn main() {
+    match sys::spawn(PROG_USER_1, SPAWN_TRACE) {
+        Some(pid) => {
+            println!("strace: tracing user_1 (pid {})", pid);
+            sys::wait(pid);
+            println!("strace: pid {} exited", pid);
+        }
+        None => eprintln!("strace: user_1 could not be started"),
+    }
+    sys::exit();
+}
@@ -1,6 +1,10 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use riscv_utils::{COUNTER_CYCLES, COUNTER_INSTRET};
 use sys_call as sys;
 use user_shared::{macros::sys_print, traits::Print, *};
 
@@ -17,8 +21,29 @@ extern "C" fn main() {
     sys_print!("786");
     "\n".print();
     println!("Hello World");
-    println!(1024);
+    println!("{} = {:#x} = {:?}", 1024, 1024, -1024i32);
     print!("Finishing\n");
-    print!('c');
+    print!("{}", 'c');
+    // The heap is provided by the allocator in user_shared
+    let mut numbers = Vec::new();
+    for i in 0..100 {
+        numbers.push(i);
+    }
+    println!("\nSum: {}", numbers.iter().sum::<usize>());
+    let mut text = String::from("Heap");
+    text.push_str(" works\n");
+    print!("{}", text);
+    // The hart counts for all processes, the kernel only for this one
+    println!(
+        "Hart: {} cycles, {} instructions",
+        counters::cycle(),
+        counters::instret()
+    );
+    if let (Some(cycles), Some(instret)) = (
+        sys::counter(COUNTER_CYCLES, 0),
+        sys::counter(COUNTER_INSTRET, 0),
+    ) {
+        println!("User 1: {} cycles, {} instructions", cycles, instret);
+    }
     sys::exit();
 }
@@ -0,0 +1,201 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr::null_mut,
+};
+use riscv_utils::{PROT_READ, PROT_WRITE};
+
+use crate::sys_call;
+
+const PAGE_SIZE: usize = 4096;
+/// Every block is a multiple of this size and aligned to it, so a free block can always hold a [FreeBlock].
+const BLOCK_ALIGN: usize = 16;
+/// The heap grows by at least this many bytes, to keep the number of brk calls low.
+const HEAP_GROW: usize = 4 * PAGE_SIZE;
+/// Free memory at the end of the heap is given back to the kernel, once it is this large.
+const HEAP_SHRINK: usize = 8 * PAGE_SIZE;
+/// Allocations of at least this size get their own mapping, so they can be given back on free.
+const MMAP_THRESHOLD: usize = 16 * PAGE_SIZE;
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// A free region of the heap. The header is stored at the start of the region itself.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    /// Free blocks, sorted by address.
+    free: *mut FreeBlock,
+    /// The current end of the heap, 0 if the heap has not been used yet.
+    end: usize,
+}
+
+/// A linked list allocator on top of the brk and mmap syscalls.
+/// User progs have only one thread, so there is no locking.
+pub struct Allocator {
+    heap: UnsafeCell<Heap>,
+}
+
+unsafe impl Sync for Allocator {}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator::new();
+
+impl Allocator {
+    pub const fn new() -> Self {
+        Allocator {
+            heap: UnsafeCell::new(Heap {
+                free: null_mut(),
+                end: 0,
+            }),
+        }
+    }
+}
+
+/// The size of the block an allocation with `layout` occupies.
+fn block_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(1), BLOCK_ALIGN)
+}
+
+/// Returns true if the allocation is served by its own mapping.
+fn is_mapped(layout: &Layout) -> bool {
+    block_size(layout) >= MMAP_THRESHOLD && layout.align() <= PAGE_SIZE
+}
+
+impl Heap {
+    /// Takes a block of `size` bytes aligned to `align` from the free list.
+    unsafe fn take(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.free;
+        while !cur.is_null() {
+            let start = cur as usize;
+            let end = start + (*cur).size;
+            let addr = align_up(start, align);
+            if addr + size <= end {
+                let next = (*cur).next;
+                self.unlink(prev, cur, next);
+                // Give the parts in front of and behind the allocation back.
+                if addr > start {
+                    self.insert(start, addr - start);
+                }
+                if addr + size < end {
+                    self.insert(addr + size, end - addr - size);
+                }
+                return addr as *mut u8;
+            }
+            prev = cur;
+            cur = (*cur).next;
+        }
+        null_mut()
+    }
+
+    unsafe fn unlink(&mut self, prev: *mut FreeBlock, cur: *mut FreeBlock, next: *mut FreeBlock) {
+        if prev.is_null() {
+            self.free = next;
+        } else {
+            (*prev).next = next;
+        }
+        (*cur).next = null_mut();
+    }
+
+    /// Adds the region to the free list and merges it with its neighbours.
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next = self.free;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        if prev.is_null() {
+            self.free = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// Asks the kernel for at least `size` more bytes and adds them to the free list.
+    unsafe fn grow(&mut self, size: usize) -> bool {
+        if self.end == 0 {
+            self.end = sys_call::brk(0);
+        }
+        let new_end = align_up(self.end + size.max(HEAP_GROW), PAGE_SIZE);
+        if sys_call::brk(new_end) != new_end {
+            return false;
+        }
+        self.insert(self.end, new_end - self.end);
+        self.end = new_end;
+        true
+    }
+
+    /// Gives the free block at the end of the heap back to the kernel, if it is large enough.
+    unsafe fn shrink(&mut self) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut last = self.free;
+        while !last.is_null() && !(*last).next.is_null() {
+            prev = last;
+            last = (*last).next;
+        }
+        if last.is_null() || last as usize + (*last).size != self.end || (*last).size < HEAP_SHRINK
+        {
+            return;
+        }
+        // Keep the start of the block, if it is not page aligned.
+        let new_end = align_up(last as usize, PAGE_SIZE);
+        if sys_call::brk(new_end) != new_end {
+            return;
+        }
+        if new_end > last as usize {
+            (*last).size = new_end - last as usize;
+        } else {
+            self.unlink(prev, last, null_mut());
+        }
+        self.end = new_end;
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = block_size(&layout);
+        if is_mapped(&layout) {
+            return match sys_call::mmap(size, PROT_READ | PROT_WRITE) {
+                Some(ptr) => ptr,
+                None => null_mut(),
+            };
+        }
+        let heap = &mut *self.heap.get();
+        let align = layout.align().max(BLOCK_ALIGN);
+        let ptr = heap.take(size, align);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // The padding for the alignment may be needed in front of the block.
+        if !heap.grow(size + align) {
+            return null_mut();
+        }
+        heap.take(size, align)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = block_size(&layout);
+        if is_mapped(&layout) {
+            sys_call::munmap(ptr, size);
+            return;
+        }
+        let heap = &mut *self.heap.get();
+        heap.insert(ptr as usize, size);
+        heap.shrink();
+    }
+}
@@ -0,0 +1,60 @@
+use core::fmt::{self, Write};
+
+use crate::sys_call;
+
+/// Bytes collected before they are printed with one Print syscall.
+const BUFFER_SIZE: usize = 128;
+
+/// Collects formatted output and prints it with the Print syscall when the buffer is full or flushed.
+/// Used by the print macros, a dropped writer prints the rest.
+pub struct Writer {
+    buf: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Writer {
+    pub const fn new() -> Self {
+        Writer {
+            buf: [0; BUFFER_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Prints the collected bytes. A char may be split between two calls, the console only sees bytes.
+    pub fn flush(&mut self) {
+        if self.len > 0 {
+            sys_call::print(&self.buf[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == BUFFER_SIZE {
+                self.flush();
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Prints the output of [format_args], called by the print macros.
+pub fn _print(args: fmt::Arguments) {
+    Writer::new().write_fmt(args).ok();
+}
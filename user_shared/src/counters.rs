@@ -0,0 +1,21 @@
+// The counters of the hart, the kernel lets the user progs read them.
+// They count for all processes on the hart, use sys_call::counter for the ones of a process.
+use core::arch::asm;
+
+/// Returns the cycles of the hart since it was reset.
+pub fn cycle() -> usize {
+    let cycle: usize;
+    unsafe {
+        asm!("rdcycle {}", out(reg) cycle);
+    }
+    cycle
+}
+
+/// Returns the instructions the hart retired since it was reset.
+pub fn instret() -> usize {
+    let instret: usize;
+    unsafe {
+        asm!("rdinstret {}", out(reg) instret);
+    }
+    instret
+}
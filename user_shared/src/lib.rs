@@ -1,7 +1,10 @@
 #![no_std]
 #![allow(dead_code)]
 
+pub mod allocator;
 pub mod asm;
+pub mod console;
+pub mod counters;
 pub mod panic_handler;
 pub mod sys_call;
 pub mod traits;
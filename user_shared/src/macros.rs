@@ -11,30 +11,52 @@ macro_rules! sys_print {
 #[allow(unused)]
 pub use sys_print;
 
-/// Prints the given expression.
-/// Currently, this function depends on syscalls.
-/// To be precise, this macro uses the macro [sys_print] with its dependency on the [Print] trait.
-/// TODO: Rewrite this macro after a successful UART-User-Process implementation.
+/// Prints a format string with its arguments like print!() of std, e.g. decimal and signed numbers or `{:?}`.
+/// The output is collected and printed with one Print syscall, see [crate::console::Writer].
+///
+/// For example:
+/// print!("Hello {}", "World");
+/// print!("{} {:?} {:#x}", -12, 'c', 1024);
+///
+/// The Output is:
+/// Hello World-12 'c' 0x400
 #[allow(unused)]
 #[macro_export]
 macro_rules! print {
-    ($($arg:expr)*) => {
-        sys_print!($($arg)*)}
+    ($($arg:tt)*) => {
+        $crate::console::_print(format_args!($($arg)*))}
 }
 #[allow(unused)]
 pub use print;
 
-/// Prints the given expression and makes a line break afterwards.
-/// Currently, this function depends on syscalls.
-/// To be precise, this macro uses the macro [sys_print] with its dependency on the [Print] trait.
-/// TODO: Rewrite this macro after a successful UART-User-Process implementation.
+/// Prints like [print] and makes a line break afterwards, in the same Print syscall.
 #[allow(unused)]
 #[macro_export]
 macro_rules! println {
-    ($($arg:expr)*) => {
-        sys_print!($($arg)*);
-        sys_print!("\n");
-    }
+    () => {
+        $crate::print!("\n")};
+    ($($arg:tt)*) => {
+        $crate::console::_print(format_args!("{}\n", format_args!($($arg)*)))}
 }
 #[allow(unused)]
 pub use println;
+
+/// Prints like [print] for error messages. There is only one console, so it ends up next to the rest.
+#[allow(unused)]
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::print!($($arg)*)}
+}
+#[allow(unused)]
+pub use eprint;
+
+/// Prints like [println] for error messages.
+#[allow(unused)]
+#[macro_export]
+macro_rules! eprintln {
+    ($($arg:tt)*) => {
+        $crate::println!($($arg)*)}
+}
+#[allow(unused)]
+pub use eprintln;
@@ -1,5 +1,10 @@
-//todo!
+use riscv_utils::EXIT_PANIC;
+
+use crate::sys_call;
+
+/// Prints where and why the process panicked and exits with [EXIT_PANIC], the kernel does not restart it.
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    loop {}
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    crate::eprintln!("\n### User Panic ###\n{}", info);
+    sys_call::exit_with(EXIT_PANIC)
 }
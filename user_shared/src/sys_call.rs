@@ -1,6 +1,9 @@
 use core::arch::asm;
 use riscv_utils as riscv;
-use riscv_utils::SysCall;
+use riscv_utils::{
+    SysCall, COUNTER_FAILED, FORK_FAILED, MAP_FAILED, SHUTDOWN_POWEROFF, SHUTDOWN_REBOOT,
+    SPAWN_FAILED, TRACE_FAILED, WAIT_RUNNING,
+};
 
 /// Funktion to transmit the µ-kernel the type of the syscall, and two parameter. 
 /// It use the register a7, a0 and a1.
@@ -34,11 +37,24 @@ pub fn get_char() -> Option<char> {
         return Some(res as u8 as char);
     }
 }
+/// Syscall to print bytes on the console, e.g. of a string.
+pub fn print(bytes: &[u8]) {
+    unsafe {
+        system_call(SysCall::Print, bytes.as_ptr() as usize, bytes.len());
+    }
+}
 /// Syscall to exit a process
 pub fn exit() {
+    exit_with(0);
+}
+/// Syscall to exit a process with a `status` other than 0, e.g. [riscv_utils::EXIT_PANIC].
+/// The kernel reports it and does not restart a process that panicked.
+pub fn exit_with(status: usize) -> ! {
     unsafe {
-        system_call(SysCall::Exit, 0, 0);
+        system_call(SysCall::Exit, status, 0);
     }
+    // The process is not scheduled again.
+    loop {}
 }
 /// Syscall to give another process preemption
 pub fn sys_yield() {
@@ -46,3 +62,97 @@ pub fn sys_yield() {
         system_call(SysCall::Yield, 0, 0);
     }
 }
+/// Syscall to set the end of the heap to `addr`.
+/// It returns the new end, or the current end if `addr` is invalid. `brk(0)` returns the current end.
+pub fn brk(addr: usize) -> usize {
+    unsafe { system_call(SysCall::Brk, addr, 0) }
+}
+/// Syscall to map `len` bytes of zeroed memory with the `prot` flags, e.g. `PROT_READ | PROT_WRITE`.
+/// It returns the start of the page aligned region.
+pub fn mmap(len: usize, prot: usize) -> Option<*mut u8> {
+    unsafe {
+        let res = system_call(SysCall::Mmap, len, prot);
+        if res == MAP_FAILED {
+            return None;
+        }
+        return Some(res as *mut u8);
+    }
+}
+/// Syscall to unmap a region returned by mmap. Parts of a region can be unmapped as well.
+pub fn munmap(addr: *mut u8, len: usize) -> bool {
+    unsafe { system_call(SysCall::Munmap, addr as usize, len) != MAP_FAILED }
+}
+/// Syscall to create a copy of the process. The copy continues at the same point.
+/// It returns the pid of the copy in the calling process and 0 in the copy.
+pub fn fork() -> Option<usize> {
+    unsafe {
+        let res = system_call(SysCall::Fork, 0, 0);
+        if res == FORK_FAILED {
+            return None;
+        }
+        return Some(res);
+    }
+}
+/// Syscall to turn off the machine, a `code` other than 0 reports a failure.
+/// It only returns, if the process is not allowed to. Forked processes are not.
+pub fn poweroff(code: u16) {
+    unsafe {
+        system_call(SysCall::Shutdown, SHUTDOWN_POWEROFF, code as usize);
+    }
+}
+/// Syscall to reset the machine.
+/// It only returns, if the process is not allowed to. Forked processes are not.
+pub fn reboot() {
+    unsafe {
+        system_call(SysCall::Shutdown, SHUTDOWN_REBOOT, 0);
+    }
+}
+/// Syscall to read the kernel log, the oldest messages first.
+/// It returns the number of bytes written to `buf`.
+pub fn read_log(buf: &mut [u8]) -> usize {
+    unsafe { system_call(SysCall::ReadLog, buf.as_mut_ptr() as usize, buf.len()) }
+}
+/// Syscall to start the process `prog`, e.g. [riscv_utils::PROG_USER_1], as a child of this one.
+/// With [riscv_utils::SPAWN_TRACE] in the `flags` its syscalls are written to the kernel log.
+/// It returns the pid of the child.
+pub fn spawn(prog: usize, flags: usize) -> Option<usize> {
+    unsafe {
+        let res = system_call(SysCall::Spawn, prog, flags);
+        if res == SPAWN_FAILED {
+            return None;
+        }
+        return Some(res);
+    }
+}
+/// Syscall to write the syscalls of the process `pid` to the kernel log, or to stop it.
+/// 0 stands for this process, the others have to be its children.
+pub fn trace(pid: usize, on: bool) -> bool {
+    unsafe { system_call(SysCall::Trace, pid, on as usize) != TRACE_FAILED }
+}
+/// Waits until the child `pid` has ended, the other processes run meanwhile.
+/// It returns false, if `pid` is not a child of this process.
+pub fn wait(pid: usize) -> bool {
+    loop {
+        match unsafe { system_call(SysCall::Wait, pid, 0) } {
+            0 => return true,
+            WAIT_RUNNING => sys_yield(),
+            _ => return false,
+        }
+    }
+}
+/// Syscall to read the counter `which`, e.g. [riscv_utils::COUNTER_CYCLES], of the process `pid`.
+/// 0 stands for this process, the others have to be its children. Only the time it ran in user mode is counted.
+pub fn counter(which: usize, pid: usize) -> Option<usize> {
+    unsafe {
+        let res = system_call(SysCall::Counter, which, pid);
+        if res == COUNTER_FAILED {
+            return None;
+        }
+        return Some(res);
+    }
+}
+/// Syscall to write the profile of the sampler to the kernel log and to start a new one.
+/// It returns the number of samples, 0 if the kernel was booted without `profile`.
+pub fn profile() -> usize {
+    unsafe { system_call(SysCall::Profile, 0, 0) }
+}